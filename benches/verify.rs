@@ -0,0 +1,38 @@
+//! Throughput of request signature verification, parsing the sender's key and applying
+//! the signature policy on every request versus looking its signing keys up in the
+//! certificate cache.
+//!
+//! Run with `cargo bench --bench verify`.
+use criterion::{Criterion, criterion_group, criterion_main};
+use nymstr_groupd::crypto_utils::{CryptoUtils, generate_cert, sign_request};
+use serde_json::{Value, json};
+use std::hint::black_box;
+
+fn verify_request(c: &mut Criterion) {
+    let keys = tempfile::tempdir().expect("temp dir");
+    let crypto =
+        CryptoUtils::new(keys.path().into(), "groupd".into(), String::new()).expect("crypto");
+    let (secret, public) = generate_cert("alice").expect("generate cert");
+    // A fetchGroup poll, the most frequent signed request
+    let mut request = json!({
+        "action": "fetchGroup",
+        "groupId": "g",
+        "lastSeenId": "0-0",
+        "timestamp": 1,
+    });
+    request["signature"] = Value::String(sign_request(&secret, &request).expect("sign"));
+
+    let mut group = c.benchmark_group("verify_request");
+    group.bench_function("uncached", |b| {
+        b.iter(|| assert!(crypto.verify_request(black_box(&public), black_box(&request))))
+    });
+    group.bench_function("cached", |b| {
+        b.iter(|| {
+            assert!(crypto.verify_cached_request("alice", black_box(&public), black_box(&request)))
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, verify_request);
+criterion_main!(benches);
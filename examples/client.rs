@@ -4,22 +4,17 @@
 //!   cargo run --example client -- <server_nym_address> [client_id]
 
 use anyhow::Result;
-use nym_sdk::mixnet::{
-    IncludedSurbs, MixnetClientBuilder, MixnetClientSender, MixnetMessageSender, Recipient,
-    StoragePaths,
+use nym_sdk::mixnet::{MixnetClientBuilder, Recipient, StoragePaths};
+use nymstr_groupd::{
+    GroupClient, ServerEvent,
+    crypto_utils::generate_cert,
+    protocol::{ErrorCode, ServerError},
 };
-use sequoia_openpgp::armor::Kind;
-use sequoia_openpgp::cert::prelude::*;
-use sequoia_openpgp::policy::StandardPolicy;
-use sequoia_openpgp::serialize::SerializeInto;
-use sequoia_openpgp::serialize::stream::{Armorer, Message, Signer as StreamSigner};
-use serde_json::{Value, json};
 use std::{
     env,
-    io::{self, BufRead, Write},
+    io::{self, BufRead},
     path::PathBuf,
 };
-use tokio_stream::StreamExt;
 use uuid::Uuid;
 
 #[tokio::main]
@@ -43,44 +38,32 @@ async fn main() -> Result<()> {
     // ------------------------------------------------------------------
     // 3. Ephemeral OpenPGP key
     // ------------------------------------------------------------------
-    let (cert, _revocation) = CertBuilder::new()
-        .add_userid(client_id.clone())
-        .add_signing_subkey()
-        .generate()?;
-
-    let keypair = cert
-        .keys()
-        .unencrypted_secret()
-        .with_policy(&StandardPolicy::new(), None)
-        .supported()
-        .alive()
-        .revoked(false)
-        .for_signing()
-        .next()
-        .expect("no signing key")
-        .key()
-        .clone()
-        .into_keypair()?;
-
-    let public_key_armored: String = String::from_utf8(SerializeInto::to_vec(&cert.armored())?)?;
+    let (secret_cert, _public_key) = generate_cert(&client_id)?;
 
     // ------------------------------------------------------------------
     // 4. Build & connect mixnet client
     // ------------------------------------------------------------------
     let builder = MixnetClientBuilder::new_with_default_storage(storage).await?;
-    let client = builder.build()?.connect_to_mixnet().await?;
-    let sender: MixnetClientSender = client.split_sender();
-    let mut maybe_receiver = Some(client);
+    let mixnet = builder.build()?.connect_to_mixnet().await?;
     let server_recipient: Recipient = server_address.parse()?;
+    let client = GroupClient::new(
+        mixnet,
+        server_recipient,
+        client_id.clone(),
+        secret_cert,
+        None,
+    )?;
+    let mut connected = false;
 
     // ------------------------------------------------------------------
     // 5. REPL
     // ------------------------------------------------------------------
     println!(
         "Enter commands:
+  info
   connect
-  send <ciphertext>
-  fetch <lastSeenId>
+  send <groupId> <ciphertext>
+  fetch <groupId> <lastSeenId>
   exit"
     );
 
@@ -89,112 +72,72 @@ async fn main() -> Result<()> {
         let line = line?;
         let mut parts = line.splitn(3, ' ');
         match parts.next() {
+            // ----------------------------------------------------------
+            // INFO
+            // ----------------------------------------------------------
+            Some("info") => match client.server_info().await {
+                Ok(info) => println!(
+                    "Server speaks protocol v{}, limits {:?}, actions: {}",
+                    info.version,
+                    info.limits,
+                    info.actions.join(", ")
+                ),
+                Err(e) => println!("ServerInfoResponse: {e}"),
+            },
+
             // ----------------------------------------------------------
             // CONNECT
             // ----------------------------------------------------------
             Some("connect") => {
-                // a) register
+                if connected {
+                    println!("Already connected");
+                    continue;
+                }
+
+                // a) register (already registered is fine: the key is on file)
                 println!("-> Registering user with server…");
-                let register_msg = json!({
-                    "action": "register",
-                    "username": client_id,
-                    "publicKey": public_key_armored,
-                })
-                .to_string()
-                .into_bytes();
-
-                sender
-                    .send_message(
-                        server_recipient.clone(),
-                        register_msg,
-                        IncludedSurbs::Amount(10),
-                    )
-                    .await?;
-
-                // Wait for registerResponse
-                if let Some(mut rec) = maybe_receiver.take() {
-                    while let Some(frame) = rec.next().await {
-                        let txt = String::from_utf8(frame.message.clone())?;
-                        let v = serde_json::from_str::<Value>(&txt)?;
-                        if v.get("action").and_then(Value::as_str) == Some("registerResponse") {
-                            let content = v["content"].as_str().unwrap_or("");
-                            println!("RegisterResponse: {content}");
-                            if content == "success" || content == "error: user already registered" {
-                                break;
-                            } else {
-                                panic!("Register failed: {content}");
-                            }
-                        }
+                match client.register().await {
+                    Ok(status) => println!("RegisterResponse: {status}"),
+                    Err(e)
+                        if e.downcast_ref::<ServerError>().map(|e| e.code)
+                            == Some(ErrorCode::AlreadyRegistered) =>
+                    {
+                        println!("RegisterResponse: {e}");
                     }
+                    Err(e) => return Err(e),
+                }
 
-                    // b) connect with detached signature
-                    println!("-> Sending connect (with PGP-signed publicKey)…");
-                    // Build a proper v4 detached PGP signature packet
-                    let signature_armored = {
-                        let mut buf = Vec::new();
-                        // ASCII-armored, detached v4 signature
-                        let armor = Armorer::new(Message::new(&mut buf))
-                            .kind(Kind::Signature)
-                            .build()?;
-                        // Create a detached signature writer_stack
-                        let mut signer = StreamSigner::new(armor, keypair.clone())?
-                            .detached()
-                            .build()?;
-                        signer.write_all(public_key_armored.as_bytes())?;
-                        signer.finalize()?;
-                        String::from_utf8(buf)?
-                    };
-
-                    let connect_msg = json!({
-                        "action": "connect",
-                        "username": client_id,
-                        "publicKey": public_key_armored,
-                        "signature": signature_armored,
-                    })
-                    .to_string()
-                    .into_bytes();
-
-                    sender
-                        .send_message(
-                            server_recipient.clone(),
-                            connect_msg,
-                            IncludedSurbs::Amount(10),
-                        )
-                        .await?;
-
-                    // spawn inbound listener
-                    let mut inbound = rec;
-                    tokio::spawn(async move {
-                        while let Some(frame) = inbound.next().await {
-                            if let Ok(text) = String::from_utf8(frame.message.clone()) {
-                                println!("[Inbound] {text}");
+                // b) sign a server challenge
+                println!("-> Sending connect (signed challenge)…");
+                client.connect().await?;
+                connected = true;
+
+                // spawn inbound listener
+                let mut events = client.subscribe();
+                tokio::spawn(async move {
+                    while let Ok(event) = events.recv().await {
+                        match event {
+                            ServerEvent::Message(message) => println!(
+                                "[Inbound] {} in {}: {}",
+                                message.sender, message.group_id, message.ciphertext
+                            ),
+                            ServerEvent::Notice { action, response } => {
+                                println!("[Inbound] {action}: {response:?}")
                             }
                         }
-                    });
-                }
-            }
-
-            // ----------------------------------------------------------
-            // JOIN GROUP (no-op for single group)
-            // ----------------------------------------------------------
-            Some("join") => {
-                // single-group server; no join action needed
+                    }
+                });
             }
 
             // ----------------------------------------------------------
             // SEND
             // ----------------------------------------------------------
             Some("send") => {
-                if let Some(cipher) = parts.next() {
-                    let msg = json!({
-                        "action": "sendGroup",
-                        "ciphertext": cipher
-                    })
-                    .to_string()
-                    .into_bytes();
-                    sender
-                        .send_message(server_recipient.clone(), msg, IncludedSurbs::Amount(10))
-                        .await?;
+                if let (Some(group_id), Some(cipher)) = (parts.next(), parts.next()) {
+                    match client.send(group_id, cipher).await {
+                        Ok(()) => println!("SendGroupResponse: success"),
+                        Err(e) => println!("SendGroupResponse: {e}"),
+                    }
                 }
             }
 
@@ -202,16 +145,15 @@ async fn main() -> Result<()> {
             // FETCH
             // ----------------------------------------------------------
             Some("fetch") => {
-                if let Some(last_seen) = parts.next() {
-                    let msg = json!({
-                        "action": "fetchGroup",
-                        "lastSeenId": last_seen
-                    })
-                    .to_string()
-                    .into_bytes();
-                    sender
-                        .send_message(server_recipient.clone(), msg, IncludedSurbs::Amount(10))
-                        .await?;
+                if let (Some(group_id), Some(last_seen)) = (parts.next(), parts.next()) {
+                    match client.fetch(group_id, last_seen).await {
+                        Ok(messages) => {
+                            for (payload, id) in messages {
+                                println!("[{id}] {payload}");
+                            }
+                        }
+                        Err(e) => println!("FetchGroupResponse: {e}"),
+                    }
                 }
             }
 
@@ -220,9 +162,10 @@ async fn main() -> Result<()> {
             // ----------------------------------------------------------
             Some("exit") => break,
 
-            _ => println!("Unknown command, use: connect | join … | send … | exit"),
+            _ => println!("Unknown command, use: info | connect | send … | fetch … | exit"),
         }
     }
 
+    client.disconnect().await;
     Ok(())
 }
@@ -0,0 +1,196 @@
+//! Live delivery of group messages to connected members: an in-process broker built on
+//! tokio broadcast channels for single-node deployments, or Redis pub/sub when several
+//! server instances share the same groups.
+use crate::transport::BoxFuture;
+use anyhow::{Result, anyhow};
+use redis::AsyncCommands;
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::broadcast;
+use tokio_stream::{
+    Stream, StreamExt,
+    wrappers::{BroadcastStream, errors::BroadcastStreamRecvError},
+};
+
+/// Payloads published on a group's channel after the subscription was made.
+pub type Subscription = Pin<Box<dyn Stream<Item = String> + Send>>;
+
+/// Publish/subscribe on one channel per group.
+pub trait Broker: Send + Sync {
+    /// Publish `payload` to the current subscribers of `group_id`'s channel.
+    fn publish<'a>(&'a self, group_id: &'a str, payload: &'a str) -> BoxFuture<'a, Result<()>>;
+
+    /// Subscribe to `group_id`'s channel. The subscription is in place once the returned
+    /// future resolves.
+    fn subscribe<'a>(&'a self, group_id: &'a str) -> BoxFuture<'a, Result<Subscription>>;
+}
+
+/// Which [`Broker`] carries live group messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BrokerKind {
+    /// In-process broadcast channels; only reaches clients of this server instance
+    Local,
+    /// Redis pub/sub, shared by every instance using the same Redis
+    Redis,
+}
+
+/// Number of messages buffered per group channel by [`LocalBroker::new`]. Subscribers
+/// that fall further behind skip the oldest messages.
+pub const DEFAULT_LOCAL_CAPACITY: usize = 256;
+
+/// In-process broker: one tokio broadcast channel per group with subscribers.
+pub struct LocalBroker {
+    channels: Mutex<HashMap<String, broadcast::Sender<String>>>,
+    capacity: usize,
+}
+
+/// Broker over Redis pub/sub, one channel per group.
+pub struct RedisBroker {
+    client: Arc<redis::Client>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_local_broker_fans_out_per_group() -> Result<()> {
+        let broker: Arc<dyn Broker> = Arc::new(LocalBroker::with_capacity(2));
+        // Nobody listens yet: publishing is not an error
+        broker.publish("g1", "lost").await?;
+
+        let mut first = broker.subscribe("g1").await?;
+        let mut second = broker.subscribe("g1").await?;
+        let mut other = broker.subscribe("g2").await?;
+        broker.publish("g1", "hello").await?;
+        assert_eq!(first.next().await.as_deref(), Some("hello"));
+        assert_eq!(second.next().await.as_deref(), Some("hello"));
+
+        // A lagging subscriber skips what it missed
+        for payload in ["a", "b", "c"] {
+            broker.publish("g1", payload).await?;
+        }
+        assert_eq!(first.next().await.as_deref(), Some("b"));
+        assert_eq!(first.next().await.as_deref(), Some("c"));
+
+        broker.publish("g2", "elsewhere").await?;
+        assert_eq!(other.next().await.as_deref(), Some("elsewhere"));
+
+        assert_eq!("local".parse::<BrokerKind>()?, BrokerKind::Local);
+        assert!("kafka".parse::<BrokerKind>().is_err());
+        Ok(())
+    }
+}
+
+impl FromStr for BrokerKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "local" => Ok(Self::Local),
+            "redis" => Ok(Self::Redis),
+            other => anyhow::bail!("unknown broker: {}", other),
+        }
+    }
+}
+
+impl Default for LocalBroker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LocalBroker {
+    pub fn new() -> Self {
+        Self::with_capacity(DEFAULT_LOCAL_CAPACITY)
+    }
+
+    /// Create a broker buffering `capacity` messages per group channel (at least one).
+    pub fn with_capacity(capacity: usize) -> Self {
+        LocalBroker {
+            channels: Mutex::new(HashMap::new()),
+            capacity: capacity.max(1),
+        }
+    }
+}
+
+impl Broker for LocalBroker {
+    fn publish<'a>(&'a self, group_id: &'a str, payload: &'a str) -> BoxFuture<'a, Result<()>> {
+        let published = match self.channels.lock() {
+            Ok(mut channels) => {
+                // A channel whose subscribers are all gone is dropped
+                if let Some(tx) = channels.get(group_id)
+                    && tx.send(payload.to_string()).is_err()
+                {
+                    channels.remove(group_id);
+                }
+                Ok(())
+            }
+            Err(_) => Err(anyhow!("broker state poisoned")),
+        };
+        Box::pin(async move { published })
+    }
+
+    fn subscribe<'a>(&'a self, group_id: &'a str) -> BoxFuture<'a, Result<Subscription>> {
+        let subscribed = match self.channels.lock() {
+            Ok(mut channels) => {
+                let rx = channels
+                    .entry(group_id.to_string())
+                    .or_insert_with(|| broadcast::channel(self.capacity).0)
+                    .subscribe();
+                let group_id = group_id.to_string();
+                let messages = BroadcastStream::new(rx).filter_map(move |item| match item {
+                    Ok(payload) => Some(payload),
+                    Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+                        log::warn!(
+                            "subscriber of group {} lagged, skipped {} messages",
+                            group_id,
+                            skipped
+                        );
+                        None
+                    }
+                });
+                Ok(Box::pin(messages) as Subscription)
+            }
+            Err(_) => Err(anyhow!("broker state poisoned")),
+        };
+        Box::pin(async move { subscribed })
+    }
+}
+
+impl RedisBroker {
+    pub fn new(client: Arc<redis::Client>) -> Self {
+        RedisBroker { client }
+    }
+}
+
+impl Broker for RedisBroker {
+    fn publish<'a>(&'a self, group_id: &'a str, payload: &'a str) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let mut conn = self.client.get_async_connection().await?;
+            let _: i64 = conn.publish(channel_key(group_id), payload).await?;
+            Ok(())
+        })
+    }
+
+    fn subscribe<'a>(&'a self, group_id: &'a str) -> BoxFuture<'a, Result<Subscription>> {
+        Box::pin(async move {
+            let conn = self.client.get_async_connection().await?;
+            let mut pubsub = conn.into_pubsub();
+            pubsub.subscribe(channel_key(group_id)).await?;
+            let messages = pubsub
+                .into_on_message()
+                .filter_map(|msg| msg.get_payload::<String>().ok());
+            Ok(Box::pin(messages) as Subscription)
+        })
+    }
+}
+
+/// Redis pub/sub channel used for live delivery to a group's connected members.
+fn channel_key(group_id: &str) -> String {
+    format!("group:{}:channel", group_id)
+}
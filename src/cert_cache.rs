@@ -0,0 +1,186 @@
+//! Bounded LRU cache of the signing keys of PGP certificates, so that verifying a user's
+//! requests neither re-parses their armored public key nor re-applies the signature
+//! policy to it every time. Request signatures themselves are not cached: the replay
+//! guard accepts each signed request once, so no signature is verified twice.
+use anyhow::Result;
+use lru::LruCache;
+use openpgp::{
+    Cert, Fingerprint,
+    packet::{
+        Key,
+        key::{PublicParts, UnspecifiedRole},
+    },
+    parse::Parse,
+    policy::StandardPolicy,
+};
+use sequoia_openpgp as openpgp;
+use std::{num::NonZeroUsize, sync::Arc, time::SystemTime};
+
+/// Number of certificates kept when no capacity is given.
+pub const DEFAULT_CAPACITY: usize = 1024;
+
+/// The keys of a certificate that the standard policy allows to sign.
+#[derive(Debug, Clone)]
+pub struct SigningKeys {
+    fingerprint: Fingerprint,
+    keys: Vec<Key<PublicParts, UnspecifiedRole>>,
+    /// Earliest expiry of the selected keys and the primary key, after which the policy
+    /// has to be applied again
+    expires: Option<SystemTime>,
+}
+
+/// The signing keys of a certificate and the armored key they were selected from.
+struct Entry {
+    armored: String,
+    keys: Arc<SigningKeys>,
+}
+
+/// Signing keys by user (or operator) name. An entry is only used while the armored key
+/// on file is byte-for-byte the one it was parsed from, so a changed key is parsed afresh
+/// even if [`CertCache::invalidate`] was not called, and only until one of its keys
+/// expires.
+pub struct CertCache {
+    entries: LruCache<String, Entry>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto_utils::generate_cert;
+
+    #[test]
+    fn test_cache_hits_and_invalidation() -> Result<()> {
+        let (_, alice) = generate_cert("alice")?;
+        let (_, rotated) = generate_cert("alice")?;
+        let (_, bob) = generate_cert("bob")?;
+        let mut cache = CertCache::new(2);
+        let first = cache.get("alice", &alice)?;
+        assert!(!first.keys().is_empty());
+        assert!(Arc::ptr_eq(&first, &cache.get("alice", &alice)?));
+
+        // A new key for the same name replaces the entry
+        let second = cache.get("alice", &rotated)?;
+        assert_ne!(first.fingerprint(), second.fingerprint());
+        assert_eq!(cache.len(), 1);
+
+        cache.invalidate("alice");
+        assert_eq!(cache.len(), 0);
+        assert!(!Arc::ptr_eq(&second, &cache.get("alice", &rotated)?));
+
+        // Keys past their expiry are selected again
+        if let Some(entry) = cache.entries.get_mut("alice") {
+            let mut expired = (*entry.keys).clone();
+            expired.expires = Some(SystemTime::UNIX_EPOCH);
+            entry.keys = Arc::new(expired);
+        }
+        assert!(!cache.get("alice", &rotated)?.expired(SystemTime::now()));
+
+        // The least recently used entry is evicted at capacity
+        cache.get("bob", &bob)?;
+        cache.get("alice", &rotated)?;
+        cache.get("carol", &alice)?;
+        assert_eq!(cache.len(), 2);
+        assert!(cache.entries.contains("alice") && !cache.entries.contains("bob"));
+        assert!(cache.get("dave", "not a key").is_err());
+        Ok(())
+    }
+}
+
+impl SigningKeys {
+    /// Select the keys of `cert` that are supported, alive and signing-capable now under
+    /// the standard policy.
+    pub fn from_cert(cert: &Cert) -> Self {
+        let policy = &StandardPolicy::new();
+        let mut expires = cert
+            .with_policy(policy, None)
+            .ok()
+            .and_then(|valid| valid.primary_key().key_expiration_time());
+        let mut keys = Vec::new();
+        for binding in cert
+            .keys()
+            .with_policy(policy, None)
+            .supported()
+            .alive()
+            .for_signing()
+        {
+            if let Some(expiry) = binding.key_expiration_time() {
+                expires = Some(expires.map_or(expiry, |earliest| earliest.min(expiry)));
+            }
+            keys.push(binding.key().clone());
+        }
+        SigningKeys {
+            fingerprint: cert.fingerprint(),
+            keys,
+            expires,
+        }
+    }
+
+    /// Parse an ASCII-armored public key and select its signing keys.
+    pub fn from_armored(armored: &str) -> Result<Self> {
+        Ok(Self::from_cert(&Cert::from_reader(armored.as_bytes())?))
+    }
+
+    /// Fingerprint of the certificate the keys were selected from.
+    pub fn fingerprint(&self) -> &Fingerprint {
+        &self.fingerprint
+    }
+
+    /// Keys a signature by the certificate's holder may be made with.
+    pub fn keys(&self) -> &[Key<PublicParts, UnspecifiedRole>] {
+        &self.keys
+    }
+
+    fn expired(&self, now: SystemTime) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+}
+
+impl Default for CertCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
+
+impl CertCache {
+    /// Create a cache holding at most `capacity` certificates (at least one).
+    pub fn new(capacity: usize) -> Self {
+        CertCache {
+            entries: LruCache::new(NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN)),
+        }
+    }
+
+    /// Signing keys of `name`'s ASCII-armored public key, from the cache when that key
+    /// was seen before and none of its keys has expired since.
+    pub fn get(&mut self, name: &str, armored: &str) -> Result<Arc<SigningKeys>> {
+        if let Some(entry) = self.entries.get(name)
+            && entry.armored == armored
+            && !entry.keys.expired(SystemTime::now())
+        {
+            return Ok(entry.keys.clone());
+        }
+        let keys = Arc::new(SigningKeys::from_armored(armored)?);
+        self.entries.put(
+            name.to_string(),
+            Entry {
+                armored: armored.to_string(),
+                keys: keys.clone(),
+            },
+        );
+        Ok(keys)
+    }
+
+    /// Drop the cached keys for `name`, e.g. after their key was replaced or removed.
+    pub fn invalidate(&mut self, name: &str) {
+        self.entries.pop(name);
+    }
+
+    /// Number of cached certificates.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether the cache is empty.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
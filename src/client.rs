@@ -0,0 +1,419 @@
+//! Async client SDK for talking to a nymstr-groupd server over the Nym mixnet.
+use crate::{
+    crypto_utils::{public_key, sign_request, verify_detached},
+    protocol::{
+        GroupMessage, PROTOCOL_VERSION, Reply, Request, Response, ServerInfo, SignedRequest,
+        canonical_json,
+    },
+};
+use anyhow::{Result, anyhow, bail};
+use chrono::Utc;
+use nym_sdk::mixnet::{
+    IncludedSurbs, MixnetClient, MixnetClientSender, MixnetMessageSender, Recipient,
+};
+use serde::Deserialize;
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    sync::{broadcast, oneshot},
+    task::JoinHandle,
+};
+use tokio_stream::StreamExt;
+use uuid::Uuid;
+
+/// Number of pushed events buffered for each subscriber before the oldest are dropped.
+const EVENT_CAPACITY: usize = 256;
+/// Reply SURBs attached to each request so the server can answer and push notices.
+const REPLY_SURBS: u32 = 10;
+/// How long a request waits for its reply unless changed with
+/// [`GroupClient::set_request_timeout`]. Mixnet round trips take seconds.
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Something the server pushed without being asked for it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServerEvent {
+    /// A live message from a group this session is subscribed to.
+    Message(GroupMessage),
+    /// A signed notice such as `groupInvite` or `groupRemoved`.
+    Notice { action: String, response: Response },
+}
+
+/// One user's session with a group server. Requests are signed with the user's secret
+/// certificate and may run concurrently: each carries a fresh `requestId` that the server
+/// echoes back. Pushed events are available through [`GroupClient::subscribe`].
+pub struct GroupClient {
+    username: String,
+    secret_cert: String,
+    public_key: String,
+    server: Recipient,
+    sender: MixnetClientSender,
+    /// Requests awaiting a reply, by `requestId`
+    pending: Arc<Mutex<HashMap<String, oneshot::Sender<Reply>>>>,
+    request_timeout: Duration,
+    events: broadcast::Sender<ServerEvent>,
+    /// Dropping this stops the reader task, which hands the mixnet client back
+    shutdown: oneshot::Sender<()>,
+    reader: JoinHandle<MixnetClient>,
+}
+
+/// A decoded inbound frame.
+#[derive(Debug, PartialEq)]
+enum Inbound {
+    Reply(Reply),
+    Event(ServerEvent),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        crypto_utils::{generate_cert, sign_detached},
+        protocol::ServerKeyRotated,
+    };
+
+    #[test]
+    fn test_classify_frames() {
+        let reply = br#"{"action":"sendGroupResponse","status":"ok","content":"success"}"#;
+        assert!(matches!(
+            classify(reply, None),
+            Some(Inbound::Reply(r)) if r.action == "sendGroupResponse"
+        ));
+        let notice =
+            br#"{"action":"groupRemoved","status":"ok","content":"{\"groupId\":\"g\",\"reason\":\"kicked\"}"}"#;
+        assert!(matches!(
+            classify(notice, None),
+            Some(Inbound::Event(ServerEvent::Notice { action, response: Response::GroupRemoved(_) }))
+                if action == "groupRemoved"
+        ));
+        let message =
+            br#"{"action":"groupMessage","status":"ok","content":"{\"groupId\":\"g\",\"sender\":\"alice\",\"ciphertext\":\"c\"}"}"#;
+        let expected = Some(Inbound::Event(ServerEvent::Message(GroupMessage {
+            group_id: "g".into(),
+            sender: "alice".into(),
+            ciphertext: "c".into(),
+        })));
+        assert_eq!(classify(message, None), expected);
+        assert_eq!(classify(b"not json", None), None);
+
+        // With a pinned key, pushed messages must be signed by it like everything else
+        let (secret, public) = generate_cert("server").unwrap();
+        let mut value: Value = serde_json::from_slice(message).unwrap();
+        assert_eq!(classify(message, Some(&public)), None);
+        let bare = br#"{"groupId":"g","sender":"alice","ciphertext":"c"}"#;
+        assert_eq!(classify(bare, Some(&public)), None);
+        let signature = sign_detached(&secret, &canonical_json(&value)).unwrap();
+        value["signature"] = signature.into();
+        let signed = serde_json::to_vec(&value).unwrap();
+        assert_eq!(classify(&signed, Some(&public)), expected);
+    }
+
+    #[test]
+    fn test_follow_key_rotation() {
+        let (old_secret, old_public) = generate_cert("server").unwrap();
+        let (_, new_public) = generate_cert("server").unwrap();
+        let rotated = |handover: String| ServerEvent::Notice {
+            action: "serverKeyRotated".into(),
+            response: Response::ServerKeyRotated(ServerKeyRotated {
+                new_key: new_public.clone(),
+                handover,
+                grace_until: 0,
+            }),
+        };
+        let mut pinned = Some(old_public.clone());
+        let forged = sign_detached(&old_secret, "another key").unwrap();
+        assert!(!accept_rotation(&rotated(forged), &mut pinned));
+        assert_eq!(pinned.as_deref(), Some(old_public.as_str()));
+        let handover = sign_detached(&old_secret, &new_public).unwrap();
+        assert!(accept_rotation(&rotated(handover), &mut pinned));
+        assert_eq!(pinned, Some(new_public));
+    }
+}
+
+impl GroupClient {
+    /// Start a session for `username` over a connected mixnet client. `secret_cert` is the
+    /// user's ASCII-armored secret certificate. When `server_key` is given, replies and
+    /// notices not signed by it are dropped; it is replaced by the new key of a
+    /// `serverKeyRotated` notice whose hand-over it signed.
+    pub fn new(
+        mixnet: MixnetClient,
+        server: Recipient,
+        username: String,
+        secret_cert: String,
+        server_key: Option<String>,
+    ) -> Result<Self> {
+        let public_key = public_key(&secret_cert)?;
+        let sender = mixnet.split_sender();
+        let pending: Arc<Mutex<HashMap<String, oneshot::Sender<Reply>>>> = Arc::default();
+        let waiting = pending.clone();
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+        let (shutdown, mut stop) = oneshot::channel::<()>();
+        let event_tx = events.clone();
+        let reader = tokio::spawn(async move {
+            let mut mixnet = mixnet;
+            let mut server_key = server_key;
+            loop {
+                tokio::select! {
+                    _ = &mut stop => break,
+                    frame = mixnet.next() => {
+                        let Some(frame) = frame else { break };
+                        match classify(&frame.message, server_key.as_deref()) {
+                            Some(Inbound::Reply(reply)) => {
+                                let waiter = reply.context.as_ref().and_then(|id| {
+                                    waiting.lock().ok().and_then(|mut map| map.remove(id))
+                                });
+                                match waiter {
+                                    Some(waiter) => {
+                                        let _ = waiter.send(reply);
+                                    }
+                                    None => log::debug!("Ignoring unexpected {} reply", reply.action),
+                                }
+                            }
+                            Some(Inbound::Event(event))
+                                if accept_rotation(&event, &mut server_key) =>
+                            {
+                                let _ = event_tx.send(event);
+                            }
+                            Some(Inbound::Event(_)) | None => {}
+                        }
+                    }
+                }
+            }
+            // Fail requests still waiting: no reply can arrive any more
+            if let Ok(mut map) = waiting.lock() {
+                map.clear();
+            }
+            mixnet
+        });
+        Ok(GroupClient {
+            username,
+            secret_cert,
+            public_key,
+            server,
+            sender,
+            pending,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            events,
+            shutdown,
+            reader,
+        })
+    }
+
+    /// Ask the server for its protocol version, actions, limits and signing key. Works
+    /// before registering, and regardless of protocol version.
+    pub async fn server_info(&self) -> Result<ServerInfo> {
+        match self.request(Request::ServerInfo).await? {
+            Response::ServerInfo(info) => Ok(info),
+            other => bail!("unexpected serverInfo reply: {:?}", other),
+        }
+    }
+
+    /// Register this user's key with the server. Resolves to `pending` until an operator
+    /// approves the registration.
+    pub async fn register(&self) -> Result<String> {
+        let request = Request::Register {
+            username: self.username.clone(),
+            public_key: self.public_key.clone(),
+        };
+        status(self.request(request).await?)
+    }
+
+    /// Authenticate this session by signing a fresh server challenge. The server then
+    /// delivers messages from the user's groups to [`GroupClient::subscribe`].
+    pub async fn connect(&self) -> Result<()> {
+        let nonce = match self.request(Request::GetChallenge).await? {
+            Response::Challenge(challenge) => challenge.nonce,
+            other => bail!("unexpected getChallenge reply: {:?}", other),
+        };
+        let request = Request::Connect {
+            username: self.username.clone(),
+            nonce,
+        };
+        status(self.request(request).await?)?;
+        Ok(())
+    }
+
+    /// End this session on the server, which stops pushing group messages to it until
+    /// the next [`GroupClient::connect`]. Idle sessions also end on their own after the
+    /// `sessionIdleSecs` advertised in [`ServerInfo::limits`].
+    pub async fn close_session(&self) -> Result<()> {
+        status(self.request(Request::Disconnect).await?)?;
+        Ok(())
+    }
+
+    /// Post an (already end-to-end encrypted) message to a group.
+    pub async fn send(&self, group_id: &str, ciphertext: &str) -> Result<()> {
+        let request = Request::SendGroup {
+            group_id: group_id.to_string(),
+            ciphertext: ciphertext.to_string(),
+        };
+        status(self.request(request).await?)?;
+        Ok(())
+    }
+
+    /// Fetch a group's messages after `last_seen_id` as `(payload, entry id)` pairs.
+    pub async fn fetch(&self, group_id: &str, last_seen_id: &str) -> Result<Vec<(String, String)>> {
+        let request = Request::FetchGroup {
+            group_id: group_id.to_string(),
+            last_seen_id: last_seen_id.to_string(),
+        };
+        match self.request(request).await? {
+            Response::Messages(batch) => Ok(batch.messages),
+            other => bail!("unexpected fetchGroup reply: {:?}", other),
+        }
+    }
+
+    /// Receive live group messages and notices pushed by the server. Events pushed
+    /// before subscribing are not replayed; use [`GroupClient::fetch`] to catch up.
+    pub fn subscribe(&self) -> broadcast::Receiver<ServerEvent> {
+        self.events.subscribe()
+    }
+
+    /// Change how long requests wait for their reply before failing.
+    pub fn set_request_timeout(&mut self, timeout: Duration) {
+        self.request_timeout = timeout;
+    }
+
+    /// Send any request and wait for the server's reply to it, failing after the request
+    /// timeout. Error replies become a [`ServerError`](crate::protocol::ServerError) that
+    /// callers can downcast to.
+    pub async fn request(&self, request: Request) -> Result<Response> {
+        let action = request.action();
+        let request_id = Uuid::new_v4().to_string();
+        let (waiter, reply) = oneshot::channel();
+        self.pending
+            .lock()
+            .map_err(|_| anyhow!("client state poisoned"))?
+            .insert(request_id.clone(), waiter);
+        if let Err(e) = self.send_request(request, &request_id).await {
+            if let Ok(mut map) = self.pending.lock() {
+                map.remove(&request_id);
+            }
+            return Err(e);
+        }
+        let reply = match tokio::time::timeout(self.request_timeout, reply).await {
+            Ok(reply) => reply
+                .map_err(|_| anyhow!("connection closed while waiting for {} reply", action))?,
+            Err(_) => {
+                // A reply arriving later finds no waiter and is ignored
+                if let Ok(mut map) = self.pending.lock() {
+                    map.remove(&request_id);
+                }
+                bail!("no {} reply within {:?}", action, self.request_timeout);
+            }
+        };
+        if let Some(error) = reply.error() {
+            return Err(error.into());
+        }
+        Response::parse(&reply.action, &reply.content)
+    }
+
+    /// Stop reading and disconnect from the mixnet.
+    pub async fn disconnect(self) {
+        drop(self.shutdown);
+        if let Ok(mixnet) = self.reader.await {
+            mixnet.disconnect().await;
+        }
+    }
+
+    /// Sign and send a request. Signed requests other than 'connect' (which signs a
+    /// challenge instead) are timestamped.
+    async fn send_request(&self, request: Request, request_id: &str) -> Result<()> {
+        let signed_request = request.is_signed();
+        let timestamped = signed_request && !matches!(request, Request::Connect { .. });
+        let mut signed = SignedRequest {
+            request,
+            version: Some(PROTOCOL_VERSION),
+            request_id: Some(request_id.to_string()),
+            timestamp: timestamped.then(|| Utc::now().timestamp_millis()),
+            signature: None,
+        };
+        if signed_request {
+            let value = serde_json::to_value(&signed)?;
+            signed.signature = Some(sign_request(&self.secret_cert, &value)?);
+        }
+        self.sender
+            .send_message(
+                self.server.clone(),
+                serde_json::to_vec(&signed)?,
+                IncludedSurbs::Amount(REPLY_SURBS),
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+/// Sort an inbound frame into a reply to one of our requests or a pushed event. Every
+/// frame, live group messages included, arrives in a [`Reply`] envelope signed by the
+/// server; a group message is a `groupMessage` notice whose content is the
+/// [`GroupMessage`] JSON.
+fn classify(frame: &[u8], server_key: Option<&str>) -> Option<Inbound> {
+    let value: Value = serde_json::from_slice(frame).ok()?;
+    let reply = Reply::deserialize(&value).ok()?;
+    if let Some(key) = server_key {
+        // Verify the frame as sent, so fields this version does not know are covered too
+        let signed = reply
+            .signature
+            .as_deref()
+            .is_some_and(|signature| verify_detached(key, &canonical_json(&value), signature));
+        if !signed {
+            log::warn!("Dropping {} with a bad server signature", reply.action);
+            return None;
+        }
+    }
+    if reply.action == "error" || reply.action.ends_with("Response") {
+        return Some(Inbound::Reply(reply));
+    }
+    if reply.action == "groupMessage" {
+        return match serde_json::from_str(&reply.content) {
+            Ok(message) => Some(Inbound::Event(ServerEvent::Message(message))),
+            Err(e) => {
+                log::warn!("Dropping undecodable group message: {}", e);
+                None
+            }
+        };
+    }
+    match Response::parse(&reply.action, &reply.content) {
+        Ok(response) => Some(Inbound::Event(ServerEvent::Notice {
+            action: reply.action,
+            response,
+        })),
+        Err(e) => {
+            log::warn!("Dropping undecodable {} notice: {}", reply.action, e);
+            None
+        }
+    }
+}
+
+/// Follow a `serverKeyRotated` notice when pinning a server key: switch to the new key
+/// if the pinned key signed it over. Returns false for a hand-over that does not verify,
+/// which is then dropped. Other events are always accepted.
+fn accept_rotation(event: &ServerEvent, server_key: &mut Option<String>) -> bool {
+    let ServerEvent::Notice {
+        response: Response::ServerKeyRotated(rotation),
+        ..
+    } = event
+    else {
+        return true;
+    };
+    let Some(pinned) = server_key.as_deref() else {
+        return true;
+    };
+    if !verify_detached(pinned, &rotation.new_key, &rotation.handover) {
+        log::warn!("Dropping serverKeyRotated with a bad hand-over signature");
+        return false;
+    }
+    *server_key = Some(rotation.new_key.clone());
+    true
+}
+
+/// Unwrap a `success` or `pending` reply.
+fn status(response: Response) -> Result<String> {
+    match response {
+        Response::Status(status) => Ok(status),
+        other => bail!("unexpected reply: {:?}", other),
+    }
+}
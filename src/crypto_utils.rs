@@ -1,22 +1,54 @@
 //! PGP-based key management and signing utilities using Sequoia OpenPGP 2.0.
-use anyhow::{Context, Result};
+use crate::{
+    cert_cache::{CertCache, SigningKeys},
+    protocol::{ServerKeyRotated, canonical_json},
+};
+use anyhow::{Context, Result, anyhow, bail};
+use chrono::Utc;
 use openpgp::{
-    PacketPile, armor::Kind as ArmorKind, cert::prelude::*, packet::Packet, parse::Parse,
-    policy::StandardPolicy, serialize::SerializeInto,
+    PacketPile,
+    armor::Kind as ArmorKind,
+    cert::prelude::*,
+    crypto::{KeyPair, Password},
+    packet::{
+        Key, Packet,
+        key::{SecretParts, UnspecifiedRole},
+    },
+    parse::Parse,
+    policy::StandardPolicy,
+    serialize::SerializeInto,
 };
 use sequoia_openpgp as openpgp;
+use serde_json::Value;
 use std::{
     fs,
     io::{Read, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
 };
 
 /// Utility for PGP key generation, detached signing, and signature verification.
+/// Clones share the signing key, so [`CryptoUtils::reload`] affects all of them.
 #[derive(Clone)]
 pub struct CryptoUtils {
     key_dir: PathBuf,
     username: String,
-    _password: String,
+    /// Passphrase protecting the secret key on disk; None stores it unencrypted
+    password: Option<Password>,
+    /// Signing key parsed once from the decrypted secret certificate. Sequoia keeps its
+    /// secret half encrypted in memory and zeroes it when the key pair is dropped.
+    signer: Arc<RwLock<Option<KeyPair>>>,
+    /// Key replaced by the last rotation, kept until its grace period ends
+    previous: Arc<RwLock<Option<PreviousKey>>>,
+    /// Parsed user and operator certificates, shared by all clones
+    certs: Arc<Mutex<CertCache>>,
+}
+
+/// A rotated-out signing key and the hand-over it signed.
+struct PreviousKey {
+    keypair: KeyPair,
+    rotation: ServerKeyRotated,
 }
 
 #[cfg(test)]
@@ -30,58 +62,261 @@ mod tests {
         let tmp = tempdir()?;
         let cu = CryptoUtils::new(tmp.path().into(), "tester".into(), "".into())?;
         let public = cu.generate_key_pair("tester")?;
+        assert_eq!(cu.load_public_key()?, public);
         let msg = "hello";
         let sig = cu.sign_message("tester", msg)?;
         assert!(cu.verify_pgp_signature(&public, msg, &sig));
         assert!(!cu.verify_pgp_signature(&public, "bad", &sig));
+        assert_eq!(cu.fingerprint(&public).map(|fp| fp.len()), Some(40));
+        assert_eq!(cu.fingerprint("not a key"), None);
+        let (secret, public) = generate_cert("client")?;
+        assert_eq!(public_key(&secret)?, public);
+        assert!(verify_detached(&public, msg, &sign_detached(&secret, msg)?));
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_request() -> Result<()> {
+        let request: Value = serde_json::from_str(
+            r#"{"action":"sendGroup","groupId":"g","ciphertext":"c","timestamp":1}"#,
+        )?;
+        let tmp = tempdir()?;
+        let cu = CryptoUtils::new(tmp.path().into(), "tester".into(), "".into())?;
+        let (secret, public) = generate_cert("tester")?;
+        let mut signed = request.clone();
+        signed["signature"] = Value::String(sign_request(&secret, &request)?);
+        assert!(cu.verify_request(&public, &signed));
+        assert!(cu.verify_cached_request("tester", &public, &signed));
+        assert!(cu.verify_cached_request("tester", &public, &signed));
+        signed["groupId"] = Value::String("other".into());
+        assert!(!cu.verify_request(&public, &signed));
+        assert!(!cu.verify_cached_request("tester", &public, &signed));
+        Ok(())
+    }
+
+    #[test]
+    fn test_secret_key_encrypted_at_rest() -> Result<()> {
+        let encrypted = |dir: &std::path::Path| -> Result<bool> {
+            let stored = Cert::from_file(dir.join("server_secret.asc"))?;
+            Ok(stored
+                .keys()
+                .secret()
+                .all(|ka| ka.key().secret().is_encrypted()))
+        };
+        let tmp = tempdir()?;
+        let cu = CryptoUtils::new(tmp.path().into(), "server".into(), "hunter2".into())?;
+        let public = cu.generate_key_pair("server")?;
+        assert!(encrypted(tmp.path())?);
+        let reopened = CryptoUtils::new(tmp.path().into(), "server".into(), "hunter2".into())?;
+        let sig = reopened.sign_message("server", "hello")?;
+        assert!(reopened.verify_pgp_signature(&public, "hello", &sig));
+        assert!(CryptoUtils::new(tmp.path().into(), "server".into(), "wrong".into()).is_err());
+        assert!(CryptoUtils::new(tmp.path().into(), "server".into(), "".into()).is_err());
+
+        // A key stored before a password was set is encrypted on the next startup
+        let plain = tempdir()?;
+        CryptoUtils::new(plain.path().into(), "server".into(), "".into())?
+            .generate_key_pair("server")?;
+        assert!(!encrypted(plain.path())?);
+        CryptoUtils::new(plain.path().into(), "server".into(), "hunter2".into())?;
+        assert!(encrypted(plain.path())?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_signing_key_in_memory_and_reload() -> Result<()> {
+        let tmp = tempdir()?;
+        let cu = CryptoUtils::new(tmp.path().into(), "server".into(), "pw".into())?;
+        let old_public = cu.generate_key_pair("server")?;
+        let shared = cu.clone();
+
+        // Signing does not touch the key file
+        let secret = tmp.path().join("server_secret.asc");
+        let stored = fs::read(&secret)?;
+        fs::remove_file(&secret)?;
+        let sig = cu.sign_message("server", "hello")?;
+        assert!(cu.verify_pgp_signature(&old_public, "hello", &sig));
+        assert!(cu.reload().is_err());
+        fs::write(&secret, stored)?;
+
+        // Swap in a new key on disk; clones sign with it after a reload
+        let other = tempdir()?;
+        let new_public = CryptoUtils::new(other.path().into(), "server".into(), "pw".into())?
+            .generate_key_pair("server")?;
+        fs::copy(other.path().join("server_secret.asc"), &secret)?;
+        assert!(cu.verify_pgp_signature(&old_public, "hi", &shared.sign_message("server", "hi")?));
+        cu.reload()?;
+        let sig = shared.sign_message("server", "hi")?;
+        assert!(cu.verify_pgp_signature(&new_public, "hi", &sig));
+        assert!(!cu.verify_pgp_signature(&old_public, "hi", &sig));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rotate_key() -> Result<()> {
+        let tmp = tempdir()?;
+        let cu = CryptoUtils::new(tmp.path().into(), "server".into(), "pw".into())?;
+        let old_public = cu.generate_key_pair("server")?;
+        assert!(cu.key_rotation().is_none());
+        let rotation = cu.rotate_key(Duration::from_secs(3600))?;
+        assert_eq!(cu.load_public_key()?, rotation.new_key);
+        assert!(verify_detached(
+            &old_public,
+            &rotation.new_key,
+            &rotation.handover
+        ));
+        let sig = cu.sign_message("server", "hi")?;
+        assert!(verify_detached(&rotation.new_key, "hi", &sig));
+        let sig = cu.sign_with_previous_key("hi")?;
+        assert!(verify_detached(&old_public, "hi", &sig));
+
+        // The previous key survives a restart within the grace period
+        let reopened = CryptoUtils::new(tmp.path().into(), "server".into(), "pw".into())?;
+        assert_eq!(reopened.key_rotation(), Some(rotation));
+        let sig = reopened.sign_with_previous_key("hi")?;
+        assert!(verify_detached(&old_public, "hi", &sig));
+
+        // No second rotation until it is over...
+        assert!(reopened.rotate_key(Duration::ZERO).is_err());
+        assert_eq!(reopened.load_public_key()?, rotation.new_key);
+
+        // ...once it is over and the previous key is dropped
+        if let Some(previous) = reopened.previous.write().unwrap().as_mut() {
+            previous.rotation.grace_until = 0;
+        }
+        assert!(reopened.key_rotation().is_none());
+        assert!(reopened.sign_with_previous_key("hi").is_err());
+        assert!(!tmp.path().join("server_previous_secret.asc").exists());
+        reopened.rotate_key(Duration::ZERO)?;
         Ok(())
     }
 }
 
 impl CryptoUtils {
-    /// Initialize with the key directory, server username, and optional passphrase.
-    pub fn new(key_dir: PathBuf, username: String, _password: String) -> Result<Self> {
+    /// Initialize with the key directory, server username, and optional passphrase (empty
+    /// for none). An existing secret key is decrypted here, so a wrong passphrase fails
+    /// at startup; a key stored unencrypted is encrypted once a passphrase is set.
+    pub fn new(key_dir: PathBuf, username: String, password: String) -> Result<Self> {
         if !key_dir.exists() {
             fs::create_dir_all(&key_dir)?;
         }
-        Ok(Self {
+        let mut crypto = Self {
             key_dir,
             username,
-            _password,
-        })
+            password: (!password.is_empty()).then(|| Password::from(password)),
+            signer: Arc::default(),
+            previous: Arc::default(),
+            certs: Arc::default(),
+        };
+        if crypto.secret_path().exists() {
+            crypto.reload()?;
+            crypto.load_previous_key()?;
+        }
+        Ok(crypto)
     }
 
     /// Generate a new PGP certificate (with signing subkey), store secret + public armor,
     /// and return the ASCII-armored public key.
     pub fn generate_key_pair(&self, _username: &str) -> Result<String> {
-        // Build a new cert with a signing subkey.
-        let (cert, _revocation) = CertBuilder::new()
-            .add_userid(self.username.clone())
-            .add_signing_subkey()
-            .generate()?;
-
-        // Persist secret certificate (unencrypted).
-        let secret_armored = String::from_utf8(cert.as_tsk().armored().to_vec()?)?;
-        fs::write(
-            self.key_dir.join(format!("{}_secret.asc", self.username)),
-            &secret_armored,
-        )?;
+        let cert = build_cert(&self.username)?;
+        let public_armored = String::from_utf8(cert.armored().to_vec()?)?;
+
+        // Persist secret certificate, encrypted with the passphrase.
+        self.store_secret_cert(&cert, &self.secret_path())?;
+        self.set_signer(signing_keypair(&cert)?)?;
 
         // Persist public certificate.
-        let public_armored = String::from_utf8(cert.armored().to_vec()?)?;
-        fs::write(
-            self.key_dir.join(format!("{}_public.asc", self.username)),
-            &public_armored,
-        )?;
+        fs::write(self.public_path(), &public_armored)?;
 
         Ok(public_armored)
     }
 
-    /// Create an ASCII-armored detached signature over `message` using the stored secret key.
+    /// Re-read and decrypt the stored secret key and sign with it from now on, e.g. after
+    /// the key files were replaced. On error the current key stays in use.
+    pub fn reload(&self) -> Result<()> {
+        let cert = self.load_secret_cert(&self.secret_path())?;
+        self.set_signer(signing_keypair(&cert)?)
+    }
+
+    /// Replace the signing key with a newly generated one. The current key signs the new
+    /// public key as a hand-over and is kept for `grace` (on disk, encrypted like the
+    /// current key) to sign the returned notice for clients that still pin it. Fails while
+    /// the grace period of the last rotation lasts, since its hand-over would be lost.
+    pub fn rotate_key(&self, grace: Duration) -> Result<ServerKeyRotated> {
+        if self.key_rotation().is_some() {
+            bail!("the grace period of the last key rotation has not ended");
+        }
+        let previous = self.current_keypair()?;
+        let cert = build_cert(&self.username)?;
+        let new_key = String::from_utf8(cert.armored().to_vec()?)?;
+        let grace_secs = i64::try_from(grace.as_secs()).unwrap_or(i64::MAX);
+        let rotation = ServerKeyRotated {
+            handover: sign_with_keypair(previous.clone(), &new_key)?,
+            new_key,
+            grace_until: Utc::now().timestamp().saturating_add(grace_secs),
+        };
+
+        // Keep the old secret key before overwriting it with the new one.
+        fs::copy(self.secret_path(), self.previous_secret_path())?;
+        fs::write(self.rotation_path(), serde_json::to_string(&rotation)?)?;
+        self.store_secret_cert(&cert, &self.secret_path())?;
+        fs::write(self.public_path(), &rotation.new_key)?;
+
+        self.set_signer(signing_keypair(&cert)?)?;
+        *self
+            .previous
+            .write()
+            .map_err(|_| anyhow!("signing key lock poisoned"))? = Some(PreviousKey {
+            keypair: previous,
+            rotation: rotation.clone(),
+        });
+        Ok(rotation)
+    }
+
+    /// The last key rotation, while its grace period lasts. Afterwards the previous key is
+    /// dropped and its files are removed.
+    pub fn key_rotation(&self) -> Option<ServerKeyRotated> {
+        let mut previous = self.previous.write().ok()?;
+        let rotation = previous.as_ref()?.rotation.clone();
+        if rotation.grace_until > Utc::now().timestamp() {
+            return Some(rotation);
+        }
+        *previous = None;
+        self.remove_previous_key_files();
+        None
+    }
+
+    /// Sign `message` with the key replaced by the last rotation, while its grace period
+    /// lasts.
+    pub fn sign_with_previous_key(&self, message: &str) -> Result<String> {
+        let now = Utc::now().timestamp();
+        let keypair = self
+            .previous
+            .read()
+            .map_err(|_| anyhow!("signing key lock poisoned"))?
+            .as_ref()
+            .filter(|previous| previous.rotation.grace_until > now)
+            .map(|previous| previous.keypair.clone())
+            .context("no previous key within its grace period")?;
+        sign_with_keypair(keypair, message)
+    }
+
+    /// Read the stored ASCII-armored public key written by [`Self::generate_key_pair`].
+    pub fn load_public_key(&self) -> Result<String> {
+        Ok(fs::read_to_string(self.public_path())?)
+    }
+
+    /// Create an ASCII-armored detached signature over `message` using the in-memory
+    /// signing key.
     pub fn sign_message(&self, _username: &str, message: &str) -> Result<String> {
-        let secret_armored =
-            fs::read_to_string(self.key_dir.join(format!("{}_secret.asc", self.username)))?;
-        sign_detached(&secret_armored, message)
+        sign_with_keypair(self.current_keypair()?, message)
+    }
+
+    /// Hex fingerprint of an ASCII-armored PGP public key, or None if it does not parse.
+    pub fn fingerprint(&self, public_key_armored: &str) -> Option<String> {
+        Cert::from_reader(public_key_armored.as_bytes())
+            .map(|cert| cert.fingerprint().to_hex())
+            .ok()
     }
 
     /// Verify an ASCII-armored PGP detached signature against a PGP public key.
@@ -91,89 +326,297 @@ impl CryptoUtils {
         message: &str,
         signature_armored: &str,
     ) -> bool {
-        log::info!(
-            "verify_pgp_signature: public_key length={}, message length={}, signature length={}",
-            public_key_armored.len(),
-            message.len(),
-            signature_armored.len()
-        );
-        let cert = match Cert::from_reader(public_key_armored.as_bytes()) {
-            Ok(c) => c,
-            Err(err) => {
-                log::error!("verify_pgp_signature: parse public key: {:?}", err);
-                return false;
+        verify_detached(public_key_armored, message, signature_armored)
+    }
+
+    /// Verify the `signature` field of a request against the canonical encoding of the
+    /// rest of the request (see [`canonical_json`]).
+    pub fn verify_request(&self, public_key_armored: &str, request: &Value) -> bool {
+        match request.get("signature").and_then(Value::as_str) {
+            Some(signature) if !signature.is_empty() => {
+                self.verify_pgp_signature(public_key_armored, &canonical_json(request), signature)
             }
-        };
-        let mut reader = openpgp::armor::Reader::from_bytes(
-            signature_armored.as_bytes(),
-            openpgp::armor::ReaderMode::Tolerant(Some(ArmorKind::Signature)),
-        );
-        let mut decoded = Vec::new();
-        if reader.read_to_end(&mut decoded).is_err() {
-            log::error!("verify_pgp_signature: dearmor signature failed");
-            return false;
+            _ => false,
         }
-        // Parse the detached signature packet(s) from the decoded data.
-        let pile = match PacketPile::from_bytes(&decoded) {
-            Ok(p) => p,
+    }
+
+    /// Like [`Self::verify_request`], but parses `public_key_armored` and selects its
+    /// signing keys only the first time it is seen for `name`; later calls use the cached
+    /// keys.
+    pub fn verify_cached_request(
+        &self,
+        name: &str,
+        public_key_armored: &str,
+        request: &Value,
+    ) -> bool {
+        let Some(signature) = request
+            .get("signature")
+            .and_then(Value::as_str)
+            .filter(|s| !s.is_empty())
+        else {
+            return false;
+        };
+        let keys = match self.certs.lock() {
+            Ok(mut certs) => certs.get(name, public_key_armored),
+            Err(_) => return false,
+        };
+        match keys {
+            Ok(keys) => verify_with_keys(&keys, &canonical_json(request), signature),
             Err(err) => {
                 log::error!(
-                    "verify_pgp_signature: parse signature packet pile: {:?}",
+                    "verify_cached_request: parse public key of {}: {:?}",
+                    name,
                     err
                 );
-                return false;
+                false
             }
+        }
+    }
+
+    /// Forget the cached certificate for `name` after their key changed or was removed.
+    pub fn forget_key(&self, name: &str) {
+        if let Ok(mut certs) = self.certs.lock() {
+            certs.invalidate(name);
+        }
+    }
+
+    fn current_keypair(&self) -> Result<KeyPair> {
+        self.signer
+            .read()
+            .map_err(|_| anyhow!("signing key lock poisoned"))?
+            .clone()
+            .context("no secret key: generate a key pair first")
+    }
+
+    fn set_signer(&self, keypair: KeyPair) -> Result<()> {
+        *self
+            .signer
+            .write()
+            .map_err(|_| anyhow!("signing key lock poisoned"))? = Some(keypair);
+        Ok(())
+    }
+
+    fn secret_path(&self) -> PathBuf {
+        self.key_dir.join(format!("{}_secret.asc", self.username))
+    }
+
+    fn public_path(&self) -> PathBuf {
+        self.key_dir.join(format!("{}_public.asc", self.username))
+    }
+
+    fn previous_secret_path(&self) -> PathBuf {
+        self.key_dir
+            .join(format!("{}_previous_secret.asc", self.username))
+    }
+
+    fn rotation_path(&self) -> PathBuf {
+        self.key_dir
+            .join(format!("{}_rotation.json", self.username))
+    }
+
+    /// Restore the previous key of a rotation whose grace period has not ended yet.
+    fn load_previous_key(&self) -> Result<()> {
+        let Ok(stored) = fs::read_to_string(self.rotation_path()) else {
+            return Ok(());
         };
-        // Extract the first signature packet.
-        let sig = match pile.into_children().find_map(|pkt| {
-            if let Packet::Signature(s) = pkt {
-                Some(s)
-            } else {
-                None
+        let rotation: ServerKeyRotated = serde_json::from_str(&stored)?;
+        if rotation.grace_until <= Utc::now().timestamp() {
+            self.remove_previous_key_files();
+            return Ok(());
+        }
+        let cert = self.load_secret_cert(&self.previous_secret_path())?;
+        *self
+            .previous
+            .write()
+            .map_err(|_| anyhow!("signing key lock poisoned"))? = Some(PreviousKey {
+            keypair: signing_keypair(&cert)?,
+            rotation,
+        });
+        Ok(())
+    }
+
+    fn remove_previous_key_files(&self) {
+        for path in [self.previous_secret_path(), self.rotation_path()] {
+            if let Err(e) = fs::remove_file(&path)
+                && e.kind() != std::io::ErrorKind::NotFound
+            {
+                log::warn!("Failed to remove {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    /// Read and decrypt a stored secret certificate.
+    fn load_secret_cert(&self, path: &Path) -> Result<Cert> {
+        let cert = Cert::from_file(path)
+            .with_context(|| format!("failed to read secret key {}", path.display()))?;
+        let encrypted = cert
+            .keys()
+            .secret()
+            .any(|ka| ka.key().secret().is_encrypted());
+        match (&self.password, encrypted) {
+            (Some(password), true) => map_secret_keys(cert, |key| {
+                if key.secret().is_encrypted() {
+                    key.decrypt_secret(password)
+                } else {
+                    Ok(key)
+                }
+            })
+            .with_context(|| format!("wrong password for secret key {}", path.display())),
+            (Some(_), false) => {
+                log::info!("Encrypting secret key {} with the password", path.display());
+                self.store_secret_cert(&cert, path)?;
+                Ok(cert)
             }
-        }) {
-            Some(s) => s,
+            (None, true) => bail!(
+                "secret key {} is encrypted but the password is empty",
+                path.display()
+            ),
+            (None, false) => Ok(cert),
+        }
+    }
+
+    /// Write a secret certificate, encrypting its secret key material with the
+    /// passphrase when one is set.
+    fn store_secret_cert(&self, cert: &Cert, path: &Path) -> Result<()> {
+        let stored = match &self.password {
+            Some(password) => map_secret_keys(cert.clone(), |key| {
+                if key.secret().is_encrypted() {
+                    Ok(key)
+                } else {
+                    key.encrypt_secret(password)
+                }
+            })?,
             None => {
-                log::error!("verify_pgp_signature: no signature packet found");
-                return false;
+                log::warn!("No password set: storing the secret key unencrypted");
+                cert.clone()
             }
         };
-        // Verify against all signing-capable keys in the certificate.
-        let policy = &StandardPolicy::new();
-        for binding in cert
-            .keys()
-            .with_policy(policy, None)
-            .supported()
-            .alive()
-            .for_signing()
-        {
-            if sig
-                .verify_message(binding.key(), message.as_bytes())
-                .is_ok()
-            {
-                return true;
-            }
+        fs::write(path, stored.as_tsk().armored().to_vec()?)?;
+        Ok(())
+    }
+}
+
+/// Apply `f` to every secret key of `cert` (e.g. to encrypt or decrypt it) and return
+/// the updated certificate.
+fn map_secret_keys(
+    cert: Cert,
+    f: impl Fn(Key<SecretParts, UnspecifiedRole>) -> Result<Key<SecretParts, UnspecifiedRole>>,
+) -> Result<Cert> {
+    let mut packets: Vec<Packet> = Vec::new();
+    for ka in cert.keys().secret() {
+        let key = f(ka.key().clone())?;
+        packets.push(if ka.primary() {
+            key.role_into_primary().into()
+        } else {
+            key.role_into_subordinate().into()
+        });
+    }
+    let (cert, _) = cert.insert_packets(packets)?;
+    Ok(cert)
+}
+
+/// Generate a new PGP certificate (with signing subkey) for `userid` and return its
+/// ASCII-armored secret and public halves.
+pub fn generate_cert(userid: &str) -> Result<(String, String)> {
+    let cert = build_cert(userid)?;
+    let secret_armored = String::from_utf8(cert.as_tsk().armored().to_vec()?)?;
+    let public_armored = String::from_utf8(cert.armored().to_vec()?)?;
+    Ok((secret_armored, public_armored))
+}
+
+/// Generate a new PGP certificate with a signing subkey for `userid`.
+fn build_cert(userid: &str) -> Result<Cert> {
+    let (cert, _revocation) = CertBuilder::new()
+        .add_userid(userid)
+        .add_signing_subkey()
+        .generate()?;
+    Ok(cert)
+}
+
+/// ASCII-armored public half of an ASCII-armored secret certificate.
+pub fn public_key(secret_cert: &str) -> Result<String> {
+    let cert = Cert::from_reader(secret_cert.as_bytes())?;
+    Ok(String::from_utf8(cert.armored().to_vec()?)?)
+}
+
+/// Sign a request for sending: a detached signature over its [`canonical_json`], to be
+/// put in its `signature` field.
+pub fn sign_request(secret_cert: &str, request: &Value) -> Result<String> {
+    sign_detached(secret_cert, &canonical_json(request))
+}
+
+/// Verify an ASCII-armored PGP detached signature against a PGP public key.
+pub fn verify_detached(public_key_armored: &str, message: &str, signature_armored: &str) -> bool {
+    log::info!(
+        "verify_detached: public_key length={}, message length={}, signature length={}",
+        public_key_armored.len(),
+        message.len(),
+        signature_armored.len()
+    );
+    let keys = match SigningKeys::from_armored(public_key_armored) {
+        Ok(keys) => keys,
+        Err(err) => {
+            log::error!("verify_detached: parse public key: {:?}", err);
+            return false;
         }
-        false
+    };
+    verify_with_keys(&keys, message, signature_armored)
+}
+
+/// Verify an ASCII-armored PGP detached signature against the signing keys of a
+/// certificate.
+fn verify_with_keys(keys: &SigningKeys, message: &str, signature_armored: &str) -> bool {
+    let mut reader = openpgp::armor::Reader::from_bytes(
+        signature_armored.as_bytes(),
+        openpgp::armor::ReaderMode::Tolerant(Some(ArmorKind::Signature)),
+    );
+    let mut decoded = Vec::new();
+    if reader.read_to_end(&mut decoded).is_err() {
+        log::error!("verify_with_keys: dearmor signature failed");
+        return false;
     }
+    // Parse the detached signature packet(s) from the decoded data.
+    let pile = match PacketPile::from_bytes(&decoded) {
+        Ok(p) => p,
+        Err(err) => {
+            log::error!("verify_with_keys: parse signature packet pile: {:?}", err);
+            return false;
+        }
+    };
+    // Extract the first signature packet.
+    let sig = match pile.into_children().find_map(|pkt| {
+        if let Packet::Signature(s) = pkt {
+            Some(s)
+        } else {
+            None
+        }
+    }) {
+        Some(s) => s,
+        None => {
+            log::error!("verify_with_keys: no signature packet found");
+            return false;
+        }
+    };
+    // Verify against all signing-capable keys in the certificate.
+    keys.keys()
+        .iter()
+        .any(|key| sig.verify_message(key, message.as_bytes()).is_ok())
 }
 
 // -----------------------------------------------------------------------------
 // PGP helper – create an ASCII-armoured *detached* signature over `payload`.
 // -----------------------------------------------------------------------------
-fn sign_detached(secret_cert: &str, payload: &str) -> Result<String> {
-    use openpgp::{
-        armor::Kind as ArmorKind,
-        policy::StandardPolicy,
-        serialize::stream::{Armorer, Message, Signer},
-        types::HashAlgorithm,
-    };
-
-    // Load certificate and pick a signing-capable subkey.
+/// Create an ASCII-armored detached signature over `payload` with the first usable
+/// signing key of an ASCII-armored secret certificate.
+pub fn sign_detached(secret_cert: &str, payload: &str) -> Result<String> {
     let cert = openpgp::Cert::from_reader(secret_cert.as_bytes())?;
+    sign_with_keypair(signing_keypair(&cert)?, payload)
+}
+
+/// Key pair of the first usable signing key of a decrypted secret certificate.
+fn signing_keypair(cert: &Cert) -> Result<KeyPair> {
     let policy = &StandardPolicy::new();
-    let keypair = cert
-        .keys()
+    cert.keys()
         .secret()
         .with_policy(policy, None)
         .supported()
@@ -183,7 +626,15 @@ fn sign_detached(secret_cert: &str, payload: &str) -> Result<String> {
         .context("no usable signing key")?
         .key()
         .clone()
-        .into_keypair()?;
+        .into_keypair()
+}
+
+/// Create an ASCII-armored detached signature over `payload` with `keypair`.
+fn sign_with_keypair(keypair: KeyPair, payload: &str) -> Result<String> {
+    use openpgp::{
+        serialize::stream::{Armorer, Message, Signer},
+        types::HashAlgorithm,
+    };
 
     // Armor & detach-sign.
     let mut buf = Vec::new();
@@ -1,4 +1,6 @@
+use crate::{permissions::Role, protocol::GroupSummary};
 use anyhow::Result;
+use chrono::Utc;
 use sqlx::{Row, SqlitePool};
 use std::path::Path;
 
@@ -33,13 +35,214 @@ mod tests {
             db.create_group("g1", "Group1", "alice", true, false)
                 .await?
         );
-        assert!(db.is_group_public("g1").await?);
+        assert_eq!(db.is_group_public("g1").await?, Some(true));
+        assert_eq!(db.is_group_public("nope").await?, None);
         assert!(db.add_group_member("g1", "alice").await?);
         let members = db.get_group_members("g1").await?;
         assert_eq!(members, vec!["alice".to_string()]);
         assert!(db.is_user_admin("g1", "alice").await?);
+        assert!(db.is_group_member("g1", "alice").await?);
+        assert!(!db.is_group_member("g1", "bob").await?);
         let groups = db.get_groups_for_user("alice").await?;
         assert_eq!(groups, vec!["g1".to_string()]);
+
+        // A group created with its owner is never left without one
+        assert!(
+            db.create_group_with_owner("g2", "Group2", "alice", false, true)
+                .await?
+        );
+        assert_eq!(db.get_member_role("g2", "alice").await?, Some(Role::Owner));
+        assert!(
+            db.create_group_with_owner("g2", "Again", "alice", true, true)
+                .await
+                .is_err()
+        );
+        assert_eq!(db.get_group_members("g2").await?, vec!["alice".to_string()]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_invite_accept_flow() -> Result<()> {
+        let db = DbUtils::new(":memory:").await?;
+        db.add_user("alice", "pk1").await?;
+        db.add_user("bob", "pk2").await?;
+        db.create_group("g1", "Group1", "alice", false, false)
+            .await?;
+        db.add_group_member("g1", "alice").await?;
+
+        assert!(!db.accept_group_invite("g1", "bob").await?);
+        assert!(db.add_group_invite("g1", "bob").await?);
+        assert!(db.is_user_invited("g1", "bob").await?);
+        assert!(db.accept_group_invite("g1", "bob").await?);
+        assert!(!db.is_user_invited("g1", "bob").await?);
+        assert!(db.is_group_member("g1", "bob").await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_list_discoverable_groups() -> Result<()> {
+        let db = DbUtils::new(":memory:").await?;
+        db.add_user("alice", "pk1").await?;
+        db.create_group("a", "Rust chat", "alice", true, true)
+            .await?;
+        db.create_group("b", "Rust_jobs", "alice", false, true)
+            .await?;
+        db.create_group("c", "Secret", "alice", false, false)
+            .await?;
+        db.create_group("d", "Rustaceans", "alice", true, true)
+            .await?;
+        db.add_group_member("a", "alice").await?;
+
+        let all = db.list_discoverable_groups(None, None, 10).await?;
+        let ids: Vec<_> = all.iter().map(|g| g.group_id.as_str()).collect();
+        assert_eq!(ids, vec!["a", "b", "d"]);
+        assert_eq!(all[0].member_count, 1);
+        assert!(all[0].is_public);
+        assert!(!all[1].is_public);
+
+        let page = db.list_discoverable_groups(None, Some("a"), 1).await?;
+        assert_eq!(page[0].group_id, "b");
+
+        let prefixed = db.list_discoverable_groups(Some("Rust_"), None, 10).await?;
+        assert_eq!(prefixed.len(), 1);
+        assert_eq!(prefixed[0].group_id, "b");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_member_roles() -> Result<()> {
+        let db = DbUtils::new(":memory:").await?;
+        db.add_user("alice", "pk1").await?;
+        db.add_user("bob", "pk2").await?;
+        db.create_group("g1", "Group1", "alice", true, false)
+            .await?;
+        db.add_group_member("g1", "alice").await?;
+        db.add_group_member("g1", "bob").await?;
+        assert_eq!(db.get_member_role("g1", "alice").await?, Some(Role::Owner));
+        assert_eq!(db.get_member_role("g1", "bob").await?, Some(Role::Member));
+        assert!(!db.is_user_admin("g1", "bob").await?);
+
+        assert!(db.set_member_role("g1", "bob", Role::Admin).await?);
+        assert!(db.is_user_admin("g1", "bob").await?);
+        assert!(!db.set_member_role("g1", "carol", Role::Admin).await?);
+        let mut members = db.get_group_members_with_roles("g1").await?;
+        members.sort();
+        assert_eq!(
+            members,
+            vec![
+                ("alice".to_string(), Role::Owner),
+                ("bob".to_string(), Role::Admin)
+            ]
+        );
+        assert_eq!(db.get_member_role("g1", "carol").await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_remove_and_ban() -> Result<()> {
+        let db = DbUtils::new(":memory:").await?;
+        db.add_user("alice", "pk1").await?;
+        db.add_user("bob", "pk2").await?;
+        db.create_group("g1", "Group1", "alice", true, false)
+            .await?;
+        db.add_group_member("g1", "bob").await?;
+        assert!(db.remove_group_member("g1", "bob").await?);
+        assert!(!db.remove_group_member("g1", "bob").await?);
+
+        db.add_group_member("g1", "bob").await?;
+        db.add_join_request("g1", "bob").await?;
+        assert!(db.ban_user("g1", "bob").await?);
+        assert!(!db.ban_user("g1", "bob").await?);
+        assert!(db.is_user_banned("g1", "bob").await?);
+        assert!(!db.is_group_member("g1", "bob").await?);
+        assert!(db.get_join_requests("g1").await?.is_empty());
+        assert!(!db.is_user_banned("g1", "alice").await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pending_listing_and_cooldown() -> Result<()> {
+        let db = DbUtils::new(":memory:").await?;
+        for name in ["carol", "alice", "bob"] {
+            db.add_pending_user(name, "pk").await?;
+        }
+        let page = db.list_pending_users(None, 2).await?;
+        let names: Vec<_> = page.iter().map(|(u, _, _)| u.as_str()).collect();
+        assert_eq!(names, vec!["alice", "bob"]);
+        assert!(page[0].2 > 0);
+        let rest = db.list_pending_users(Some("bob"), 2).await?;
+        assert_eq!(rest.len(), 1);
+        assert_eq!(rest[0].0, "carol");
+
+        assert_eq!(db.get_registration_cooldown("carol").await?, None);
+        let until = Utc::now().timestamp() + 60;
+        db.set_registration_cooldown("carol", until).await?;
+        assert_eq!(db.get_registration_cooldown("carol").await?, Some(until));
+        db.set_registration_cooldown("carol", until - 120).await?;
+        assert_eq!(db.get_registration_cooldown("carol").await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_operators() -> Result<()> {
+        let db = DbUtils::new(":memory:").await?;
+        assert!(db.get_operators().await?.is_empty());
+        assert!(db.add_operator("root", "pk1").await?);
+        assert!(!db.add_operator("root", "pk2").await?);
+        assert!(db.add_operator("backup", "pk3").await?);
+        assert_eq!(
+            db.get_operators().await?,
+            vec![
+                ("backup".to_string(), "pk3".to_string()),
+                ("root".to_string(), "pk1".to_string())
+            ]
+        );
+        assert!(db.remove_operator("backup").await?);
+        assert!(!db.remove_operator("backup").await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_group_messages() -> Result<()> {
+        let db = DbUtils::new(":memory:").await?;
+        let first = db.add_group_message("g1", "one").await?;
+        db.add_group_message("g2", "elsewhere").await?;
+        let second = db.add_group_message("g1", "two").await?;
+        let third = db.add_group_message("g1", "three").await?;
+        assert!(first < second && second < third);
+
+        let all = db.get_group_messages_after("g1", 0, 10).await?;
+        assert_eq!(
+            all,
+            vec![
+                (first, "one".to_string()),
+                (second, "two".to_string()),
+                (third, "three".to_string())
+            ]
+        );
+        let page = db.get_group_messages_after("g1", first, 1).await?;
+        assert_eq!(page, vec![(second, "two".to_string())]);
+        assert!(
+            db.get_group_messages_after("g1", third, 10)
+                .await?
+                .is_empty()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_join_requests() -> Result<()> {
+        let db = DbUtils::new(":memory:").await?;
+        db.add_user("alice", "pk1").await?;
+        db.add_user("bob", "pk2").await?;
+        db.create_group("g1", "Group1", "alice", false, false)
+            .await?;
+
+        assert!(db.add_join_request("g1", "bob").await?);
+        assert!(!db.add_join_request("g1", "bob").await?);
+        assert_eq!(db.get_join_requests("g1").await?, vec!["bob".to_string()]);
+        assert!(db.remove_join_request("g1", "bob").await?);
+        assert!(db.get_join_requests("g1").await?.is_empty());
         Ok(())
     }
 }
@@ -69,6 +272,7 @@ impl DbUtils {
             CREATE TABLE IF NOT EXISTS group_members (
                 groupId   TEXT NOT NULL,
                 username  TEXT NOT NULL,
+                role      TEXT NOT NULL DEFAULT 'member',
                 PRIMARY KEY (groupId, username),
                 FOREIGN KEY (groupId) REFERENCES groups(groupId),
                 FOREIGN KEY (username) REFERENCES users(username)
@@ -80,14 +284,71 @@ impl DbUtils {
                 FOREIGN KEY (groupId) REFERENCES groups(groupId),
                 FOREIGN KEY (username) REFERENCES users(username)
             );
+            CREATE TABLE IF NOT EXISTS group_join_requests (
+                groupId  TEXT NOT NULL,
+                username TEXT NOT NULL,
+                PRIMARY KEY (groupId, username),
+                FOREIGN KEY (groupId) REFERENCES groups(groupId),
+                FOREIGN KEY (username) REFERENCES users(username)
+            );
+            CREATE TABLE IF NOT EXISTS group_bans (
+                groupId  TEXT NOT NULL,
+                username TEXT NOT NULL,
+                PRIMARY KEY (groupId, username),
+                FOREIGN KEY (groupId) REFERENCES groups(groupId),
+                FOREIGN KEY (username) REFERENCES users(username)
+            );
             CREATE TABLE IF NOT EXISTS pending_users (
-                username  TEXT PRIMARY KEY,
+                username    TEXT PRIMARY KEY,
+                publicKey   TEXT NOT NULL,
+                requestedAt INTEGER NOT NULL DEFAULT 0
+            );
+            CREATE TABLE IF NOT EXISTS registration_cooldowns (
+                username TEXT PRIMARY KEY,
+                until    INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS operators (
+                name      TEXT PRIMARY KEY,
                 publicKey TEXT NOT NULL
             );
+            CREATE TABLE IF NOT EXISTS group_messages (
+                id        INTEGER PRIMARY KEY AUTOINCREMENT,
+                groupId   TEXT NOT NULL,
+                payload   TEXT NOT NULL,
+                createdAt INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS group_messages_by_group
+                ON group_messages (groupId, id);
             "#,
         )
         .execute(&pool)
         .await?;
+        // Databases created before per-member roles lack the role column; the group
+        // creator recorded in groups.admin becomes the owner.
+        if add_column_if_missing(
+            &pool,
+            "group_members",
+            "role",
+            "TEXT NOT NULL DEFAULT 'member'",
+        )
+        .await?
+        {
+            sqlx::query(
+                r#"
+                UPDATE group_members SET role = 'owner'
+                WHERE username = (SELECT admin FROM groups WHERE groups.groupId = group_members.groupId);
+                "#,
+            )
+            .execute(&pool)
+            .await?;
+        }
+        add_column_if_missing(
+            &pool,
+            "pending_users",
+            "requestedAt",
+            "INTEGER NOT NULL DEFAULT 0",
+        )
+        .await?;
         log::info!("DbUtils initialized with db_url={}", db_url);
         Ok(DbUtils { pool })
     }
@@ -149,15 +410,128 @@ impl DbUtils {
         Ok(success)
     }
 
-    /// Add a member to a group. Returns true on success.
-    /// Add a member to a group. Returns true on success.
+    /// Create a new group together with its creator's owner membership, in one
+    /// transaction. Returns true on success.
+    pub async fn create_group_with_owner(
+        &self,
+        group_id: &str,
+        group_name: &str,
+        owner: &str,
+        is_public: bool,
+        is_discoverable: bool,
+    ) -> Result<bool> {
+        log::info!(
+            "create_group_with_owner: group_id={}, group_name={}, owner={}",
+            group_id,
+            group_name,
+            owner
+        );
+        let mut tx = self.pool.begin().await?;
+        let res = sqlx::query(
+            "INSERT INTO groups (groupId, groupName, admin, isPublic, isDiscoverable) VALUES (?, ?, ?, ?, ?)"
+        )
+        .bind(group_id)
+        .bind(group_name)
+        .bind(owner)
+        .bind(is_public as i64)
+        .bind(is_discoverable as i64)
+        .execute(&mut *tx)
+        .await?;
+        if res.rows_affected() == 0 {
+            return Ok(false);
+        }
+        sqlx::query("INSERT INTO group_members (groupId, username, role) VALUES (?, ?, 'owner')")
+            .bind(group_id)
+            .bind(owner)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    /// Add a member to a group. The group creator joins as owner, everyone else as a
+    /// regular member. Returns true on success.
     pub async fn add_group_member(&self, group_id: &str, username: &str) -> Result<bool> {
         log::info!(
             "add_group_member: group_id={}, username={}",
             group_id,
             username
         );
-        let res = sqlx::query("INSERT INTO group_members (groupId, username) VALUES (?, ?)")
+        let res = sqlx::query(
+            r#"
+            INSERT INTO group_members (groupId, username, role)
+            SELECT ?1, ?2, CASE WHEN admin = ?2 THEN 'owner' ELSE 'member' END
+            FROM groups WHERE groupId = ?1
+            "#,
+        )
+        .bind(group_id)
+        .bind(username)
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    /// Get a member's role in a group, or None if they are not a member.
+    pub async fn get_member_role(&self, group_id: &str, username: &str) -> Result<Option<Role>> {
+        log::info!(
+            "get_member_role: group_id={}, username={}",
+            group_id,
+            username
+        );
+        let row = sqlx::query("SELECT role FROM group_members WHERE groupId = ? AND username = ?")
+            .bind(group_id)
+            .bind(username)
+            .fetch_optional(&self.pool)
+            .await?;
+        row.map(|r| r.get::<String, _>(0).parse()).transpose()
+    }
+
+    /// Change a member's role. Returns false if the user is not a member.
+    pub async fn set_member_role(
+        &self,
+        group_id: &str,
+        username: &str,
+        role: Role,
+    ) -> Result<bool> {
+        log::info!(
+            "set_member_role: group_id={}, username={}, role={}",
+            group_id,
+            username,
+            role
+        );
+        let res =
+            sqlx::query("UPDATE group_members SET role = ? WHERE groupId = ? AND username = ?")
+                .bind(role.as_str())
+                .bind(group_id)
+                .bind(username)
+                .execute(&self.pool)
+                .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    /// Get all members of a group together with their roles.
+    pub async fn get_group_members_with_roles(
+        &self,
+        group_id: &str,
+    ) -> Result<Vec<(String, Role)>> {
+        log::info!("get_group_members_with_roles: group_id={}", group_id);
+        let rows = sqlx::query("SELECT username, role FROM group_members WHERE groupId = ?")
+            .bind(group_id)
+            .fetch_all(&self.pool)
+            .await?;
+        rows.into_iter()
+            .map(|r| Ok((r.get(0), r.get::<String, _>(1).parse()?)))
+            .collect()
+    }
+
+    /// Remove a member from a group. Returns true if the user was a member.
+    pub async fn remove_group_member(&self, group_id: &str, username: &str) -> Result<bool> {
+        log::info!(
+            "remove_group_member: group_id={}, username={}",
+            group_id,
+            username
+        );
+        let res = sqlx::query("DELETE FROM group_members WHERE groupId = ? AND username = ?")
             .bind(group_id)
             .bind(username)
             .execute(&self.pool)
@@ -165,6 +539,45 @@ impl DbUtils {
         Ok(res.rows_affected() > 0)
     }
 
+    /// Ban a user from a group, dropping any membership, invite or join request.
+    /// Returns false if the user was already banned.
+    pub async fn ban_user(&self, group_id: &str, username: &str) -> Result<bool> {
+        log::info!("ban_user: group_id={}, username={}", group_id, username);
+        let mut tx = self.pool.begin().await?;
+        let res = sqlx::query("INSERT OR IGNORE INTO group_bans (groupId, username) VALUES (?, ?)")
+            .bind(group_id)
+            .bind(username)
+            .execute(&mut *tx)
+            .await?;
+        for table in ["group_members", "group_invites", "group_join_requests"] {
+            sqlx::query(&format!(
+                "DELETE FROM {} WHERE groupId = ? AND username = ?",
+                table
+            ))
+            .bind(group_id)
+            .bind(username)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    /// Check if a user is banned from a group.
+    pub async fn is_user_banned(&self, group_id: &str, username: &str) -> Result<bool> {
+        log::info!(
+            "is_user_banned: group_id={}, username={}",
+            group_id,
+            username
+        );
+        let row = sqlx::query("SELECT 1 FROM group_bans WHERE groupId = ? AND username = ?")
+            .bind(group_id)
+            .bind(username)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.is_some())
+    }
+
     /// Get all usernames of members in a group.
     pub async fn get_group_members(&self, group_id: &str) -> Result<Vec<String>> {
         log::info!("get_group_members: group_id={}", group_id);
@@ -175,14 +588,14 @@ impl DbUtils {
         Ok(rows.into_iter().map(|r| r.get(0)).collect())
     }
 
-    /// Check if the user is the admin of the group.
-    pub async fn is_user_admin(&self, group_id: &str, username: &str) -> Result<bool> {
+    /// Check if a user is a member of a group.
+    pub async fn is_group_member(&self, group_id: &str, username: &str) -> Result<bool> {
         log::info!(
-            "is_user_admin: group_id={}, username={}",
+            "is_group_member: group_id={}, username={}",
             group_id,
             username
         );
-        let row = sqlx::query("SELECT 1 FROM groups WHERE groupId = ? AND admin = ?")
+        let row = sqlx::query("SELECT 1 FROM group_members WHERE groupId = ? AND username = ?")
             .bind(group_id)
             .bind(username)
             .fetch_optional(&self.pool)
@@ -190,14 +603,31 @@ impl DbUtils {
         Ok(row.is_some())
     }
 
-    /// Check if a group is public.
-    pub async fn is_group_public(&self, group_id: &str) -> Result<bool> {
+    /// Check if the user is an owner or admin of the group.
+    pub async fn is_user_admin(&self, group_id: &str, username: &str) -> Result<bool> {
+        log::info!(
+            "is_user_admin: group_id={}, username={}",
+            group_id,
+            username
+        );
+        let row = sqlx::query(
+            "SELECT 1 FROM group_members WHERE groupId = ? AND username = ? AND role IN ('owner', 'admin')",
+        )
+        .bind(group_id)
+        .bind(username)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.is_some())
+    }
+
+    /// Check if a group is public. Returns None if there is no such group.
+    pub async fn is_group_public(&self, group_id: &str) -> Result<Option<bool>> {
         log::info!("is_group_public: group_id={}", group_id);
         let row = sqlx::query("SELECT isPublic FROM groups WHERE groupId = ?")
             .bind(group_id)
-            .fetch_one(&self.pool)
+            .fetch_optional(&self.pool)
             .await?;
-        Ok(row.get::<i64, _>(0) != 0)
+        Ok(row.map(|r| r.get::<i64, _>(0) != 0))
     }
 
     /// Check if a group is discoverable.
@@ -210,6 +640,57 @@ impl DbUtils {
         Ok(row.get::<i64, _>(0) != 0)
     }
 
+    /// List discoverable groups ordered by id, starting after `cursor` and optionally
+    /// restricted to names beginning with `name_prefix`.
+    pub async fn list_discoverable_groups(
+        &self,
+        name_prefix: Option<&str>,
+        cursor: Option<&str>,
+        limit: u32,
+    ) -> Result<Vec<GroupSummary>> {
+        log::info!(
+            "list_discoverable_groups: name_prefix={:?}, cursor={:?}, limit={}",
+            name_prefix,
+            cursor,
+            limit
+        );
+        // Escape LIKE wildcards so the prefix is matched literally
+        let pattern = name_prefix.map(|p| {
+            let escaped = p
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_");
+            format!("{}%", escaped)
+        });
+        let rows = sqlx::query(
+            r#"
+            SELECT g.groupId, g.groupName, g.isPublic, COUNT(m.username)
+            FROM groups g
+            LEFT JOIN group_members m ON m.groupId = g.groupId
+            WHERE g.isDiscoverable = 1
+              AND (?1 IS NULL OR g.groupName LIKE ?1 ESCAPE '\')
+              AND (?2 IS NULL OR g.groupId > ?2)
+            GROUP BY g.groupId
+            ORDER BY g.groupId
+            LIMIT ?3
+            "#,
+        )
+        .bind(pattern)
+        .bind(cursor)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|r| GroupSummary {
+                group_id: r.get(0),
+                group_name: r.get(1),
+                is_public: r.get::<i64, _>(2) != 0,
+                member_count: r.get(3),
+            })
+            .collect())
+    }
+
     /// Add an invite for a user to join a private group.
     pub async fn add_group_invite(&self, group_id: &str, username: &str) -> Result<bool> {
         log::info!(
@@ -255,19 +736,145 @@ impl DbUtils {
         Ok(row.is_some())
     }
 
-    /// Add a new pending user registration. Returns true on success.
+    /// Turn a pending invite into a group membership. Returns false if no invite existed.
+    pub async fn accept_group_invite(&self, group_id: &str, username: &str) -> Result<bool> {
+        log::info!(
+            "accept_group_invite: group_id={}, username={}",
+            group_id,
+            username
+        );
+        let mut tx = self.pool.begin().await?;
+        let res = sqlx::query("DELETE FROM group_invites WHERE groupId = ? AND username = ?")
+            .bind(group_id)
+            .bind(username)
+            .execute(&mut *tx)
+            .await?;
+        if res.rows_affected() == 0 {
+            return Ok(false);
+        }
+        sqlx::query("INSERT OR IGNORE INTO group_members (groupId, username) VALUES (?, ?)")
+            .bind(group_id)
+            .bind(username)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    /// Record a user's request to join a private group. Returns false if already requested.
+    pub async fn add_join_request(&self, group_id: &str, username: &str) -> Result<bool> {
+        log::info!(
+            "add_join_request: group_id={}, username={}",
+            group_id,
+            username
+        );
+        let res = sqlx::query(
+            "INSERT OR IGNORE INTO group_join_requests (groupId, username) VALUES (?, ?)",
+        )
+        .bind(group_id)
+        .bind(username)
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    /// Remove a user's join request. Returns true if one existed.
+    pub async fn remove_join_request(&self, group_id: &str, username: &str) -> Result<bool> {
+        log::info!(
+            "remove_join_request: group_id={}, username={}",
+            group_id,
+            username
+        );
+        let res = sqlx::query("DELETE FROM group_join_requests WHERE groupId = ? AND username = ?")
+            .bind(group_id)
+            .bind(username)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    /// Get all usernames with a pending join request for a group.
+    pub async fn get_join_requests(&self, group_id: &str) -> Result<Vec<String>> {
+        log::info!("get_join_requests: group_id={}", group_id);
+        let rows = sqlx::query(
+            "SELECT username FROM group_join_requests WHERE groupId = ? ORDER BY username",
+        )
+        .bind(group_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(|r| r.get(0)).collect())
+    }
+
     /// Add a new pending user registration. Returns true on success.
     pub async fn add_pending_user(&self, username: &str, public_key: &str) -> Result<bool> {
         log::info!("add_pending_user: username={}", username);
-        let res =
-            sqlx::query("INSERT OR IGNORE INTO pending_users (username, publicKey) VALUES (?, ?)")
-                .bind(username)
-                .bind(public_key)
-                .execute(&self.pool)
-                .await?;
+        let res = sqlx::query(
+            "INSERT OR IGNORE INTO pending_users (username, publicKey, requestedAt) VALUES (?, ?, ?)",
+        )
+        .bind(username)
+        .bind(public_key)
+        .bind(Utc::now().timestamp())
+        .execute(&self.pool)
+        .await?;
         Ok(res.rows_affected() > 0)
     }
 
+    /// List pending registrations ordered by username, starting after `cursor`.
+    /// Returns (username, publicKey, requestedAt) with requestedAt in Unix seconds.
+    pub async fn list_pending_users(
+        &self,
+        cursor: Option<&str>,
+        limit: u32,
+    ) -> Result<Vec<(String, String, i64)>> {
+        log::info!("list_pending_users: cursor={:?}, limit={}", cursor, limit);
+        let rows = sqlx::query(
+            r#"
+            SELECT username, publicKey, requestedAt FROM pending_users
+            WHERE (?1 IS NULL OR username > ?1)
+            ORDER BY username
+            LIMIT ?2
+            "#,
+        )
+        .bind(cursor)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|r| (r.get(0), r.get(1), r.get(2)))
+            .collect())
+    }
+
+    /// Block new registrations for `username` until the given Unix time.
+    pub async fn set_registration_cooldown(&self, username: &str, until: i64) -> Result<()> {
+        log::info!(
+            "set_registration_cooldown: username={}, until={}",
+            username,
+            until
+        );
+        sqlx::query(
+            "INSERT OR REPLACE INTO registration_cooldowns (username, until) VALUES (?, ?)",
+        )
+        .bind(username)
+        .bind(until)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Unix time until which registrations for `username` are blocked, if still in force.
+    pub async fn get_registration_cooldown(&self, username: &str) -> Result<Option<i64>> {
+        log::info!("get_registration_cooldown: username={}", username);
+        let row = sqlx::query(
+            "SELECT until FROM registration_cooldowns WHERE username = ? AND until > ?",
+        )
+        .bind(username)
+        .bind(Utc::now().timestamp())
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(|r| r.get(0)))
+    }
+
     /// Retrieve a pending user by username. Returns the public key if found.
     pub async fn get_pending_user(&self, username: &str) -> Result<Option<String>> {
         log::info!("get_pending_user: username={}", username);
@@ -297,4 +904,106 @@ impl DbUtils {
             .await?;
         Ok(rows.into_iter().map(|r| r.get(0)).collect())
     }
+
+    /// Add a server operator allowed to approve registrations. Returns false if the name
+    /// is taken.
+    pub async fn add_operator(&self, name: &str, public_key: &str) -> Result<bool> {
+        log::info!("add_operator: name={}", name);
+        let res = sqlx::query("INSERT OR IGNORE INTO operators (name, publicKey) VALUES (?, ?)")
+            .bind(name)
+            .bind(public_key)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    /// Remove a server operator. Returns true if the operator existed.
+    pub async fn remove_operator(&self, name: &str) -> Result<bool> {
+        log::info!("remove_operator: name={}", name);
+        let res = sqlx::query("DELETE FROM operators WHERE name = ?")
+            .bind(name)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    /// Get all server operators. Returns (name, publicKey) pairs.
+    pub async fn get_operators(&self) -> Result<Vec<(String, String)>> {
+        log::info!("get_operators");
+        let rows = sqlx::query("SELECT name, publicKey FROM operators ORDER BY name")
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows.into_iter().map(|r| (r.get(0), r.get(1))).collect())
+    }
+
+    /// Append a message to a group's history. Returns its id, which increases with
+    /// every message stored.
+    pub async fn add_group_message(&self, group_id: &str, payload: &str) -> Result<i64> {
+        log::info!("add_group_message: group_id={}", group_id);
+        let res = sqlx::query(
+            "INSERT INTO group_messages (groupId, payload, createdAt) VALUES (?, ?, ?)",
+        )
+        .bind(group_id)
+        .bind(payload)
+        .bind(Utc::now().timestamp())
+        .execute(&self.pool)
+        .await?;
+        Ok(res.last_insert_rowid())
+    }
+
+    /// Messages of a group stored after the message `after_id`, oldest first.
+    /// Returns (id, payload).
+    pub async fn get_group_messages_after(
+        &self,
+        group_id: &str,
+        after_id: i64,
+        limit: u32,
+    ) -> Result<Vec<(i64, String)>> {
+        log::info!(
+            "get_group_messages_after: group_id={}, after_id={}, limit={}",
+            group_id,
+            after_id,
+            limit
+        );
+        let rows = sqlx::query(
+            r#"
+            SELECT id, payload FROM group_messages
+            WHERE groupId = ? AND id > ?
+            ORDER BY id
+            LIMIT ?
+            "#,
+        )
+        .bind(group_id)
+        .bind(after_id)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(|r| (r.get(0), r.get(1))).collect())
+    }
+}
+
+/// Add a column to an existing table unless it is already there. Returns true if added.
+async fn add_column_if_missing(
+    pool: &SqlitePool,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<bool> {
+    let exists = sqlx::query("SELECT 1 FROM pragma_table_info(?) WHERE name = ?")
+        .bind(table)
+        .bind(column)
+        .fetch_optional(pool)
+        .await?
+        .is_some();
+    if exists {
+        return Ok(false);
+    }
+    log::info!("DbUtils: adding column {}.{}", table, column);
+    sqlx::query(&format!(
+        "ALTER TABLE {} ADD COLUMN {} {}",
+        table, column, definition
+    ))
+    .execute(pool)
+    .await?;
+    Ok(true)
 }
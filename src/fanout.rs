@@ -0,0 +1,445 @@
+//! Fan-out of live group messages to connected sessions: one broker subscription per
+//! group channel, dispatching to a bounded queue per session so that a slow client only
+//! ever delays itself.
+use crate::{broker::Broker, transport::ReplySender};
+use nym_sdk::mixnet::AnonymousSenderTag;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    sync::mpsc::{self, error::TrySendError},
+    task::JoinHandle,
+};
+use tokio_stream::StreamExt;
+
+/// Messages a session may have waiting before newer ones are dropped for it.
+pub const DEFAULT_QUEUE_CAPACITY: usize = 64;
+/// Wait before subscribing again to a channel whose subscription ended, doubled after
+/// each failed attempt up to [`MAX_RESUBSCRIBE_DELAY`].
+const RESUBSCRIBE_DELAY: Duration = Duration::from_millis(100);
+const MAX_RESUBSCRIBE_DELAY: Duration = Duration::from_secs(30);
+
+/// A message waiting in a session's queue: the group it was published to and its payload.
+type Queued = Arc<(String, String)>;
+/// Queues of the sessions subscribed to one group, by sender tag.
+type Members = Arc<Mutex<HashMap<AnonymousSenderTag, mpsc::Sender<Queued>>>>;
+
+/// Routes each group's channel to the sessions subscribed to it.
+pub struct Fanout {
+    broker: Arc<dyn Broker>,
+    sender: Arc<dyn ReplySender>,
+    queue_capacity: usize,
+    groups: HashMap<String, GroupFeed>,
+    sessions: HashMap<AnonymousSenderTag, SessionQueue>,
+}
+
+/// The single subscription to a group's channel and the sessions it dispatches to.
+struct GroupFeed {
+    members: Members,
+    task: JoinHandle<()>,
+}
+
+/// A session's outbound queue, drained by its own forwarding task.
+struct SessionQueue {
+    queue: mpsc::Sender<Queued>,
+    /// Groups the session is subscribed to. The forwarding task checks it, so messages
+    /// still queued for a group the session left are never delivered.
+    groups: Arc<Mutex<HashSet<String>>>,
+    task: JoinHandle<()>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        broker::{LocalBroker, Subscription},
+        transport::{BoxFuture, Loopback, Transport},
+    };
+    use anyhow::Result;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::sync::{Semaphore, mpsc::UnboundedSender};
+    use tokio_stream::wrappers::UnboundedReceiverStream;
+
+    /// Broker whose subscriptions can all be ended at once, like a dropped Redis
+    /// connection, and whose next `failures` subscribe calls fail.
+    #[derive(Default)]
+    struct CuttableBroker {
+        subscribers: Mutex<Vec<UnboundedSender<String>>>,
+        failures: AtomicUsize,
+    }
+
+    /// Reply sender holding each reply until the gate lets it through.
+    struct GatedSender {
+        inner: Arc<dyn ReplySender>,
+        gate: Semaphore,
+        calls: AtomicUsize,
+    }
+
+    impl CuttableBroker {
+        fn cut(&self) {
+            self.subscribers.lock().unwrap().clear();
+        }
+
+        fn subscriber_count(&self) -> usize {
+            self.subscribers.lock().unwrap().len()
+        }
+    }
+
+    impl Broker for CuttableBroker {
+        fn publish<'a>(&'a self, _: &'a str, payload: &'a str) -> BoxFuture<'a, Result<()>> {
+            for subscriber in self.subscribers.lock().unwrap().iter() {
+                let _ = subscriber.send(payload.to_string());
+            }
+            Box::pin(async { Ok(()) })
+        }
+
+        fn subscribe<'a>(&'a self, _: &'a str) -> BoxFuture<'a, Result<Subscription>> {
+            let failing = self
+                .failures
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok();
+            if failing {
+                return Box::pin(async { Err(anyhow::anyhow!("broker unavailable")) });
+            }
+            let (tx, rx) = mpsc::unbounded_channel();
+            self.subscribers.lock().unwrap().push(tx);
+            Box::pin(async move { Ok(Box::pin(UnboundedReceiverStream::new(rx)) as Subscription) })
+        }
+    }
+
+    impl ReplySender for GatedSender {
+        fn send_reply(
+            &self,
+            recipient: AnonymousSenderTag,
+            message: Vec<u8>,
+        ) -> BoxFuture<'_, Result<()>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move {
+                self.gate.acquire().await?.forget();
+                self.inner.send_reply(recipient, message).await
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_one_subscription_per_group() -> Result<()> {
+        let transport = Loopback::new();
+        let broker: Arc<dyn Broker> = Arc::new(LocalBroker::new());
+        let mut fanout = Fanout::new(broker.clone(), transport.reply_sender(), 4);
+        let mut alice = transport.client();
+        let mut bob = transport.client();
+
+        fanout.subscribe(alice.tag(), "g1").await;
+        fanout.subscribe(bob.tag(), "g1").await;
+        fanout.subscribe(bob.tag(), "g1").await;
+        fanout.subscribe(bob.tag(), "g2").await;
+        assert_eq!(fanout.groups.len(), 2);
+        assert_eq!(fanout.groups["g1"].members.lock().unwrap().len(), 2);
+
+        broker.publish("g1", "hello").await?;
+        assert_eq!(alice.recv().await.as_deref(), Some(&b"hello"[..]));
+        assert_eq!(bob.recv().await.as_deref(), Some(&b"hello"[..]));
+
+        // The last member leaving a group drops its subscription
+        fanout.unsubscribe(alice.tag(), "g1");
+        assert!(!fanout.is_subscribed(alice.tag(), "g1"));
+        assert!(!fanout.sessions.contains_key(&alice.tag()));
+        fanout.remove_session(bob.tag());
+        assert!(fanout.groups.is_empty() && fanout.sessions.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_feed_resubscribes_when_its_subscription_ends() -> Result<()> {
+        let transport = Loopback::new();
+        let broker = Arc::new(CuttableBroker::default());
+        let mut fanout = Fanout::new(broker.clone(), transport.reply_sender(), 4);
+        let mut alice = transport.client();
+        let wait_for_subscriber = || {
+            tokio::time::timeout(Duration::from_secs(5), async {
+                while broker.subscriber_count() == 0 {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+        };
+
+        // The broker being down when the first member joins does not lose the group
+        broker.failures.store(1, Ordering::SeqCst);
+        fanout.subscribe(alice.tag(), "g1").await;
+        assert_eq!(broker.subscriber_count(), 0);
+        wait_for_subscriber().await?;
+        broker.publish("g1", "hello").await?;
+        assert_eq!(alice.recv().await.as_deref(), Some(&b"hello"[..]));
+
+        broker.cut();
+        wait_for_subscriber().await?;
+        // No session subscribed again, yet Alice still gets the group's messages
+        broker.publish("g1", "again").await?;
+        assert_eq!(alice.recv().await.as_deref(), Some(&b"again"[..]));
+        assert_eq!(broker.subscriber_count(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_kicked_member_gets_nothing_still_queued() -> Result<()> {
+        let transport = Loopback::new();
+        let broker: Arc<dyn Broker> = Arc::new(LocalBroker::new());
+        let sender = Arc::new(GatedSender {
+            inner: transport.reply_sender(),
+            gate: Semaphore::new(0),
+            calls: AtomicUsize::new(0),
+        });
+        let mut fanout = Fanout::new(broker.clone(), sender.clone(), 4);
+        let mut alice = transport.client();
+        fanout.subscribe(alice.tag(), "g1").await;
+        fanout.subscribe(alice.tag(), "g2").await;
+
+        // One message is being sent to Alice while two more wait in her queue
+        broker.publish("g1", "first").await?;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while sender.calls.load(Ordering::SeqCst) == 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        broker.publish("g1", "second").await?;
+        broker.publish("g1", "third").await?;
+        let queue = fanout.sessions[&alice.tag()].queue.clone();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while queue.capacity() > 2 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+
+        fanout.unsubscribe(alice.tag(), "g1");
+        broker.publish("g2", "other").await?;
+        sender.gate.add_permits(10);
+        assert_eq!(alice.recv().await.as_deref(), Some(&b"first"[..]));
+        assert_eq!(alice.recv().await.as_deref(), Some(&b"other"[..]));
+        Ok(())
+    }
+}
+
+impl Fanout {
+    /// Create a fan-out over `broker`, delivering through `sender` with at most
+    /// `queue_capacity` messages waiting per session (at least one).
+    pub fn new(
+        broker: Arc<dyn Broker>,
+        sender: Arc<dyn ReplySender>,
+        queue_capacity: usize,
+    ) -> Self {
+        Fanout {
+            broker,
+            sender,
+            queue_capacity: queue_capacity.max(1),
+            groups: HashMap::new(),
+            sessions: HashMap::new(),
+        }
+    }
+
+    /// Whether messages of `group_id` are forwarded to `sender_tag`.
+    pub fn is_subscribed(&self, sender_tag: AnonymousSenderTag, group_id: &str) -> bool {
+        self.sessions
+            .get(&sender_tag)
+            .is_some_and(|session| session.is_subscribed(group_id))
+    }
+
+    /// Forward the messages published on `group_id`'s channel to `sender_tag`, subscribing
+    /// to the channel if no session did yet. Unless the broker could not be reached,
+    /// nothing published after this returns is missed; otherwise the feed keeps trying to
+    /// subscribe in the background.
+    pub async fn subscribe(&mut self, sender_tag: AnonymousSenderTag, group_id: &str) {
+        // A feed whose task stopped (its members' lock was poisoned) is replaced, keeping
+        // its members
+        if self
+            .groups
+            .get(group_id)
+            .is_none_or(|feed| feed.task.is_finished())
+        {
+            let members = self
+                .groups
+                .get(group_id)
+                .map(|feed| feed.members.clone())
+                .unwrap_or_default();
+            let feed = self.open_feed(group_id, members).await;
+            self.groups.insert(group_id.to_string(), feed);
+        }
+
+        let sender = self.sender.clone();
+        let capacity = self.queue_capacity;
+        let session = self
+            .sessions
+            .entry(sender_tag)
+            .or_insert_with(|| SessionQueue::spawn(sender_tag, sender, capacity));
+        if let Ok(mut groups) = session.groups.lock() {
+            groups.insert(group_id.to_string());
+        }
+        if let Some(feed) = self.groups.get(group_id)
+            && let Ok(mut members) = feed.members.lock()
+        {
+            members.insert(sender_tag, session.queue.clone());
+        }
+    }
+
+    /// Stop forwarding `group_id`'s messages to `sender_tag`, including those still in
+    /// its queue.
+    pub fn unsubscribe(&mut self, sender_tag: AnonymousSenderTag, group_id: &str) {
+        let Some(session) = self.sessions.get(&sender_tag) else {
+            return;
+        };
+        let left_all = match session.groups.lock() {
+            Ok(mut groups) => {
+                groups.remove(group_id);
+                groups.is_empty()
+            }
+            Err(_) => true,
+        };
+        if left_all && let Some(session) = self.sessions.remove(&sender_tag) {
+            session.task.abort();
+        }
+        self.leave_feed(sender_tag, group_id);
+    }
+
+    /// Stop forwarding anything to `sender_tag` and drop its queue.
+    pub fn remove_session(&mut self, sender_tag: AnonymousSenderTag) {
+        let Some(session) = self.sessions.remove(&sender_tag) else {
+            return;
+        };
+        session.task.abort();
+        let groups = match session.groups.lock() {
+            Ok(mut groups) => std::mem::take(&mut *groups),
+            Err(_) => return,
+        };
+        for group_id in &groups {
+            self.leave_feed(sender_tag, group_id);
+        }
+    }
+
+    /// Subscribe to `group_id`'s channel and spawn the task dispatching its messages to
+    /// `members`. The task subscribes again whenever the subscription ends (e.g. the Redis
+    /// connection dropped) or could not be made in the first place, until the feed is
+    /// dropped.
+    async fn open_feed(&self, group_id: &str, members: Members) -> GroupFeed {
+        let mut messages = match self.broker.subscribe(group_id).await {
+            Ok(messages) => Some(messages),
+            Err(e) => {
+                log::error!("subscribing to group {} failed: {}", group_id, e);
+                None
+            }
+        };
+        let broker = self.broker.clone();
+        let dispatch_to = members.clone();
+        let group_id = group_id.to_string();
+        let task = tokio::spawn(async move {
+            loop {
+                if let Some(mut subscription) = messages.take() {
+                    while let Some(payload) = subscription.next().await {
+                        let queued = Arc::new((group_id.clone(), payload));
+                        let Ok(mut members) = dispatch_to.lock() else {
+                            return;
+                        };
+                        members.retain(|tag, queue| match queue.try_send(queued.clone()) {
+                            Ok(()) => true,
+                            // The session can fetch what it missed from the history
+                            Err(TrySendError::Full(_)) => {
+                                log::warn!(
+                                    "queue of {} full, dropping a message of {}",
+                                    tag,
+                                    group_id
+                                );
+                                true
+                            }
+                            Err(TrySendError::Closed(_)) => false,
+                        });
+                    }
+                    // Messages published until the new subscription is in place are only in
+                    // the history
+                    log::warn!(
+                        "subscription to group {} ended, subscribing again",
+                        group_id
+                    );
+                }
+                let mut delay = RESUBSCRIBE_DELAY;
+                messages = Some(loop {
+                    tokio::time::sleep(delay).await;
+                    match broker.subscribe(&group_id).await {
+                        Ok(messages) => break messages,
+                        Err(e) => {
+                            log::error!("subscribing to group {} failed: {}", group_id, e);
+                            delay = (delay * 2).min(MAX_RESUBSCRIBE_DELAY);
+                        }
+                    }
+                });
+            }
+        });
+        GroupFeed { members, task }
+    }
+
+    /// Remove `sender_tag` from `group_id`'s feed, dropping the feed once it has no members.
+    fn leave_feed(&mut self, sender_tag: AnonymousSenderTag, group_id: &str) {
+        let Some(feed) = self.groups.get(group_id) else {
+            return;
+        };
+        let empty = match feed.members.lock() {
+            Ok(mut members) => {
+                members.remove(&sender_tag);
+                members.is_empty()
+            }
+            Err(_) => true,
+        };
+        if empty && let Some(feed) = self.groups.remove(group_id) {
+            feed.task.abort();
+        }
+    }
+}
+
+impl Drop for Fanout {
+    fn drop(&mut self) {
+        for feed in self.groups.values() {
+            feed.task.abort();
+        }
+        for session in self.sessions.values() {
+            session.task.abort();
+        }
+    }
+}
+
+impl SessionQueue {
+    /// Create the queue of `sender_tag` and the task forwarding it through `sender`.
+    fn spawn(
+        sender_tag: AnonymousSenderTag,
+        sender: Arc<dyn ReplySender>,
+        capacity: usize,
+    ) -> Self {
+        let (queue, mut pending) = mpsc::channel::<Queued>(capacity);
+        let groups = Arc::new(Mutex::new(HashSet::new()));
+        let subscribed = groups.clone();
+        let task = tokio::spawn(async move {
+            while let Some(queued) = pending.recv().await {
+                let (group_id, payload) = &*queued;
+                let member = subscribed
+                    .lock()
+                    .is_ok_and(|groups| groups.contains(group_id));
+                if member {
+                    let _ = sender
+                        .send_reply(sender_tag, payload.as_bytes().to_vec())
+                        .await;
+                }
+            }
+        });
+        SessionQueue {
+            queue,
+            groups,
+            task,
+        }
+    }
+
+    fn is_subscribed(&self, group_id: &str) -> bool {
+        self.groups
+            .lock()
+            .is_ok_and(|groups| groups.contains(group_id))
+    }
+}
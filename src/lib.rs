@@ -0,0 +1,20 @@
+//! nymstr-groupd: a group chat server over the Nym mixnet.
+//!
+//! The library exports the protocol model ([`protocol`]), the signing helpers in
+//! [`crypto_utils`] and an async client SDK ([`GroupClient`]) for applications, alongside
+//! the server components used by the `nymstr-groupd` binary.
+pub mod broker;
+pub mod cert_cache;
+pub mod client;
+pub mod crypto_utils;
+pub mod db_utils;
+pub mod fanout;
+pub mod message_store;
+pub mod message_utils;
+pub mod permissions;
+pub mod protocol;
+pub mod replay;
+pub mod sessions;
+pub mod transport;
+
+pub use client::{GroupClient, ServerEvent};
@@ -1,17 +1,18 @@
-mod crypto_utils;
-mod db_utils;
 mod log_config;
-mod message_utils;
 
-use crate::crypto_utils::CryptoUtils;
-use crate::db_utils::DbUtils;
 use crate::log_config::init_logging;
-use crate::message_utils::MessageUtils;
+use anyhow::Context;
 use nym_sdk::mixnet::{MixnetClientBuilder, StoragePaths};
+use nymstr_groupd::broker::{Broker, BrokerKind, LocalBroker, RedisBroker};
+use nymstr_groupd::crypto_utils::CryptoUtils;
+use nymstr_groupd::db_utils::DbUtils;
+use nymstr_groupd::message_store::{MessageStore, MessageStoreKind, RedisMessageStore};
+use nymstr_groupd::message_utils::{GroupCreationPolicy, MessageUtils};
+use nymstr_groupd::transport::Transport;
 use redis::Client as RedisClient;
 use std::path::PathBuf;
 use std::sync::Arc;
-use tokio_stream::StreamExt;
+use std::time::Duration;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -34,6 +35,17 @@ async fn main() -> anyhow::Result<()> {
     }
     let db = DbUtils::new(&db_path).await?;
 
+    // First run: seed the operator list from ADMIN_PK so someone can approve registrations
+    if db.get_operators().await?.is_empty() {
+        match std::env::var("ADMIN_PK") {
+            Ok(admin_key) if !admin_key.trim().is_empty() => {
+                db.add_operator("admin", admin_key.trim()).await?;
+                log::info!("Bootstrapped operator 'admin' from ADMIN_PK");
+            }
+            _ => log::warn!("No operators configured and ADMIN_PK unset; nobody can approve"),
+        }
+    }
+
     // Prepare key storage for signing
     let keys_dir = std::env::var("KEYS_DIR").unwrap_or_else(|_| "storage/keys".to_string());
     std::fs::create_dir_all(&keys_dir)?;
@@ -55,7 +67,10 @@ async fn main() -> anyhow::Result<()> {
         PathBuf::from(&keys_dir),
         client_id.clone(),
         password.clone(),
-    )?;
+    )
+    .with_context(|| {
+        format!("failed to load the server secret key; check the password in {secret_path}")
+    })?;
     // Ensure the server has a PGP keypair (for signing replies).
     let pub_key_path = PathBuf::from(&keys_dir).join(format!("{}_public.asc", client_id));
     if !pub_key_path.exists() {
@@ -65,6 +80,21 @@ async fn main() -> anyhow::Result<()> {
         );
         crypto.generate_key_pair(&client_id)?;
     }
+    // Reload the signing key from KEYS_DIR on SIGHUP, e.g. after replacing the key files
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        let crypto = crypto.clone();
+        let mut hangup = signal(SignalKind::hangup())?;
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                match crypto.reload() {
+                    Ok(()) => log::info!("Reloaded the server signing key"),
+                    Err(e) => log::error!("Failed to reload the server signing key: {:#}", e),
+                }
+            }
+        });
+    }
     let storage_dir =
         std::env::var("NYM_SDK_STORAGE").unwrap_or_else(|_| format!("storage/{}", client_id));
     // Ensure mixnet SDK storage directory exists
@@ -74,26 +104,60 @@ async fn main() -> anyhow::Result<()> {
     // Build and connect the mixnet client
     let builder = MixnetClientBuilder::new_with_default_storage(storage_paths).await?;
     let client_inner = builder.build()?.connect_to_mixnet().await?;
-    let sender = client_inner.split_sender();
+    let sender = client_inner.reply_sender();
     let address = client_inner.nym_address();
     log::info!("Connected to mixnet. Nym Address: {}", address);
 
     // process incoming messages until shutdown signal or stream end
     let mut client_stream = client_inner;
 
-    // Connect to Redis for group pub/sub
+    // Redis, for the message store and broker backends that use it
     let redis_url = std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1/".to_string());
     let redis_client = Arc::new(RedisClient::open(redis_url)?);
 
+    // Keep group message history in Redis Streams or in the server database
+    let message_store: Arc<dyn MessageStore> = match std::env::var("MESSAGE_STORE")
+        .unwrap_or_else(|_| "redis".to_string())
+        .parse()?
+    {
+        MessageStoreKind::Redis => Arc::new(RedisMessageStore::new(redis_client.clone())),
+        MessageStoreKind::Sqlite => Arc::new(db.clone()),
+    };
+
+    // Deliver live messages in-process, or over Redis pub/sub to share groups across
+    // several server instances
+    let broker: Arc<dyn Broker> = match std::env::var("BROKER")
+        .unwrap_or_else(|_| "redis".to_string())
+        .parse()?
+    {
+        BrokerKind::Local => Arc::new(LocalBroker::new()),
+        BrokerKind::Redis => Arc::new(RedisBroker::new(redis_client.clone())),
+    };
+
+    // Decide who may create groups: any approved user, or only server operators
+    let group_creation_policy: GroupCreationPolicy = std::env::var("GROUP_CREATION_POLICY")
+        .unwrap_or_else(|_| "approved".to_string())
+        .parse()?;
+
     // Start processing incoming messages
-    let mut message_utils =
-        MessageUtils::new(client_id.clone(), sender, db, crypto, redis_client.clone());
+    let mut message_utils = MessageUtils::new(
+        client_id.clone(),
+        sender,
+        db,
+        crypto,
+        message_store,
+        broker,
+        group_creation_policy,
+    );
+    // Expire sessions that send no signed request for this long
+    if let Ok(secs) = std::env::var("SESSION_IDLE_TIMEOUT_SECS") {
+        let secs: u64 = secs
+            .parse()
+            .context("SESSION_IDLE_TIMEOUT_SECS must be a number of seconds")?;
+        message_utils.set_session_idle_timeout(Duration::from_secs(secs));
+    }
     tokio::select! {
-        _ = async {
-            while let Some(msg) = client_stream.next().await {
-                message_utils.process_received_message(msg).await;
-            }
-        } => {},
+        _ = message_utils.serve(&mut client_stream) => {},
         _ = tokio::signal::ctrl_c() => {
             log::info!("Shutting down mixnet client.");
             client_stream.disconnect().await;
@@ -0,0 +1,168 @@
+//! Storage of group message history, read back by 'fetchGroup': Redis Streams, or the
+//! server's SQLite database for deployments that run without Redis.
+use crate::{db_utils::DbUtils, transport::BoxFuture};
+use anyhow::{Context, Result};
+use redis::{
+    AsyncCommands,
+    streams::{StreamReadOptions, StreamReadReply},
+};
+use std::{str::FromStr, sync::Arc};
+
+/// Append-only message history per group. Entry ids are opaque to clients, which page
+/// through a group's history by passing back the last id they have seen ("0" for the
+/// start).
+pub trait MessageStore: Send + Sync {
+    /// Append `payload` to the history of `group_id`, returning the new entry's id.
+    fn append<'a>(&'a self, group_id: &'a str, payload: &'a str) -> BoxFuture<'a, Result<String>>;
+
+    /// Up to `limit` entries of `group_id` stored after `after_id`, oldest first, as
+    /// (payload, id) pairs.
+    fn read_after<'a>(
+        &'a self,
+        group_id: &'a str,
+        after_id: &'a str,
+        limit: usize,
+    ) -> BoxFuture<'a, Result<Vec<(String, String)>>>;
+}
+
+/// Which [`MessageStore`] the server keeps group history in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageStoreKind {
+    /// One Redis Stream per group
+    Redis,
+    /// The `group_messages` table of the server database
+    Sqlite,
+}
+
+/// Group history in Redis Streams, one stream per group with the payload in its
+/// `message` field.
+pub struct RedisMessageStore {
+    client: Arc<redis::Client>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sqlite_store_pages_by_id() -> Result<()> {
+        let db = DbUtils::new(":memory:").await?;
+        let store: Arc<dyn MessageStore> = Arc::new(db);
+        let first = store.append("g1", "one").await?;
+        let second = store.append("g1", "two").await?;
+
+        let all = store.read_after("g1", "0", 10).await?;
+        assert_eq!(
+            all,
+            vec![
+                ("one".to_string(), first.clone()),
+                ("two".to_string(), second)
+            ]
+        );
+        assert_eq!(store.read_after("g1", &first, 10).await?.len(), 1);
+        assert!(store.read_after("g2", "0", 10).await?.is_empty());
+        // Redis stream ids are not valid here
+        assert!(store.read_after("g1", "1700000000000-0", 10).await.is_err());
+
+        assert_eq!(
+            "sqlite".parse::<MessageStoreKind>()?,
+            MessageStoreKind::Sqlite
+        );
+        assert!("memcached".parse::<MessageStoreKind>().is_err());
+        Ok(())
+    }
+}
+
+impl FromStr for MessageStoreKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "redis" => Ok(Self::Redis),
+            "sqlite" => Ok(Self::Sqlite),
+            other => anyhow::bail!("unknown message store: {}", other),
+        }
+    }
+}
+
+impl RedisMessageStore {
+    pub fn new(client: Arc<redis::Client>) -> Self {
+        RedisMessageStore { client }
+    }
+}
+
+impl MessageStore for RedisMessageStore {
+    fn append<'a>(&'a self, group_id: &'a str, payload: &'a str) -> BoxFuture<'a, Result<String>> {
+        Box::pin(async move {
+            let mut conn = self.client.get_async_connection().await?;
+            // XADD <stream_key> * message <payload>
+            let id: String = conn
+                .xadd(stream_key(group_id), "*", &[("message", payload)])
+                .await?;
+            Ok(id)
+        })
+    }
+
+    fn read_after<'a>(
+        &'a self,
+        group_id: &'a str,
+        after_id: &'a str,
+        limit: usize,
+    ) -> BoxFuture<'a, Result<Vec<(String, String)>>> {
+        Box::pin(async move {
+            let mut conn = self.client.get_async_connection().await?;
+            // Non-blocking XREAD from after_id, one batch at a time
+            let options = StreamReadOptions::default().count(limit);
+            let reply: Option<StreamReadReply> = conn
+                .xread_options(&[stream_key(group_id)], &[after_id], &options)
+                .await?;
+            let mut messages = Vec::new();
+            for sk in reply.into_iter().flat_map(|r| r.keys) {
+                for entry in sk.ids {
+                    if let Some(redis::Value::Data(bytes)) = entry.map.get("message")
+                        && let Ok(s) = String::from_utf8(bytes.clone())
+                    {
+                        messages.push((s, entry.id.clone()));
+                    }
+                }
+            }
+            Ok(messages)
+        })
+    }
+}
+
+/// Group history in the server database, with the row ids as entry ids.
+impl MessageStore for DbUtils {
+    fn append<'a>(&'a self, group_id: &'a str, payload: &'a str) -> BoxFuture<'a, Result<String>> {
+        Box::pin(async move {
+            let id = self.add_group_message(group_id, payload).await?;
+            Ok(id.to_string())
+        })
+    }
+
+    fn read_after<'a>(
+        &'a self,
+        group_id: &'a str,
+        after_id: &'a str,
+        limit: usize,
+    ) -> BoxFuture<'a, Result<Vec<(String, String)>>> {
+        Box::pin(async move {
+            let after_id: i64 = after_id
+                .parse()
+                .with_context(|| format!("invalid message id: {}", after_id))?;
+            let limit = u32::try_from(limit).unwrap_or(u32::MAX);
+            let rows = self
+                .get_group_messages_after(group_id, after_id, limit)
+                .await?;
+            Ok(rows
+                .into_iter()
+                .map(|(id, payload)| (payload, id.to_string()))
+                .collect())
+        })
+    }
+}
+
+/// Redis Stream key holding the message history of a group.
+fn stream_key(group_id: &str) -> String {
+    format!("group:{}:stream", group_id)
+}
@@ -1,40 +1,139 @@
-use crate::{crypto_utils::CryptoUtils, db_utils::DbUtils};
-use nym_sdk::mixnet::{
-    AnonymousSenderTag, MixnetClientSender, MixnetMessageSender, ReconstructedMessage,
+use crate::{
+    broker::Broker,
+    crypto_utils::CryptoUtils,
+    db_utils::DbUtils,
+    fanout::{DEFAULT_QUEUE_CAPACITY, Fanout},
+    message_store::MessageStore,
+    permissions::{Permission, Role},
+    protocol::{
+        ACTIONS, Challenge, ErrorCode, GroupCreated, GroupInvite, GroupJoined, GroupMessage,
+        GroupPage, GroupRemoved, JoinRequest, JoinRequests, Limits, MessageBatch, PROTOCOL_VERSION,
+        PendingPage, PendingUser, Reply, Request, Response, RoleChanged, ServerInfo,
+        ServerKeyRotated, SignedRequest, Status, canonical_json,
+    },
+    replay::ReplayGuard,
+    sessions::{DEFAULT_IDLE_TIMEOUT, SessionManager},
+    transport::{ReplySender, Transport},
 };
-use redis::AsyncCommands;
-use serde_json::{Value, json};
-use std::{collections::HashMap, env, sync::Arc};
-use tokio_stream::StreamExt;
+use nym_sdk::mixnet::{AnonymousSenderTag, ReconstructedMessage};
+use serde::Deserialize;
+use serde_json::Value;
+use std::{
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use uuid::Uuid;
+
+/// Page size used by 'listGroups' when the client does not ask for one.
+const LIST_GROUPS_DEFAULT_LIMIT: u32 = 50;
+/// Upper bound on the page size a client may request from 'listGroups'.
+const LIST_GROUPS_MAX_LIMIT: u32 = 200;
+
+/// Page size used by 'listPending' when the operator does not ask for one.
+const LIST_PENDING_DEFAULT_LIMIT: u32 = 50;
+/// Upper bound on the page size an operator may request from 'listPending'.
+const LIST_PENDING_MAX_LIMIT: u32 = 200;
+
+/// Largest ciphertext accepted by 'sendGroup', in bytes.
+const MAX_MESSAGE_BYTES: usize = 64 * 1024;
+/// How often sessions are checked for idle expiry.
+const SESSION_SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Most stored messages returned by one 'fetchGroup'; clients page with the last id.
+const FETCH_BATCH_SIZE: usize = 100;
+
+/// How long the previous server key keeps vouching for its successor after a rotation.
+const KEY_ROTATION_GRACE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Server policy deciding who may create new groups.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GroupCreationPolicy {
+    /// Any approved user may create groups.
+    Approved,
+    /// Only server operators may create groups.
+    OperatorsOnly,
+}
+
+impl FromStr for GroupCreationPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "approved" => Ok(Self::Approved),
+            // "admin" predates operators being stored in the database
+            "operators" | "admin" => Ok(Self::OperatorsOnly),
+            other => anyhow::bail!("unknown group creation policy: {}", other),
+        }
+    }
+}
 
 /// Handler for incoming mixnet messages and command processing for group chat server.
 pub struct MessageUtils {
     db: DbUtils,
     crypto: CryptoUtils,
-    sender: MixnetClientSender,
+    sender: Arc<dyn ReplySender>,
     client_id: String,
-    redis_client: Arc<redis::Client>,
-    /// Currently active clients: sender tags mapped to username
-    active_clients: HashMap<AnonymousSenderTag, String>,
+    /// Group message history read by 'fetchGroup'
+    store: Arc<dyn MessageStore>,
+    /// Live delivery of group messages to connected members
+    broker: Arc<dyn Broker>,
+    group_creation_policy: GroupCreationPolicy,
+    /// Connected sessions by sender tag
+    sessions: SessionManager,
+    /// Forwarding of group channels to the sessions subscribed to them
+    fanout: Fanout,
+    /// Issued connect challenges and recently accepted signatures
+    replay: ReplayGuard,
+    /// `requestId` of the request being handled, echoed as `context` on its replies
+    request_id: Option<String>,
 }
 
 impl MessageUtils {
-    /// Create a new MessageUtils instance.
-    /// Create a new MessageUtils instance with Redis client for pub/sub.
+    /// Create a new MessageUtils instance keeping group history in `store` and delivering
+    /// live messages through `broker`.
     pub fn new(
         client_id: String,
-        sender: MixnetClientSender,
+        sender: Arc<dyn ReplySender>,
         db: DbUtils,
         crypto: CryptoUtils,
-        redis_client: Arc<redis::Client>,
+        store: Arc<dyn MessageStore>,
+        broker: Arc<dyn Broker>,
+        group_creation_policy: GroupCreationPolicy,
     ) -> Self {
+        let fanout = Fanout::new(broker.clone(), sender.clone(), DEFAULT_QUEUE_CAPACITY);
         MessageUtils {
             db,
             crypto,
             sender,
             client_id,
-            redis_client,
-            active_clients: HashMap::new(),
+            store,
+            broker,
+            group_creation_policy,
+            sessions: SessionManager::new(DEFAULT_IDLE_TIMEOUT),
+            fanout,
+            replay: ReplayGuard::new(),
+            request_id: None,
+        }
+    }
+
+    /// Expire sessions that go without a signed request for longer than `timeout`.
+    pub fn set_session_idle_timeout(&mut self, timeout: Duration) {
+        self.sessions.set_idle_timeout(timeout);
+    }
+
+    /// Handle requests from `transport` until it is closed, expiring idle sessions along
+    /// the way.
+    pub async fn serve(&mut self, transport: &mut dyn Transport) {
+        let mut sweep = tokio::time::interval(SESSION_SWEEP_INTERVAL);
+        loop {
+            tokio::select! {
+                msg = transport.next_request() => match msg {
+                    Some(msg) => self.process_received_message(msg).await,
+                    None => break,
+                },
+                _ = sweep.tick() => self.expire_idle_sessions(),
+            }
         }
     }
 
@@ -55,451 +154,1809 @@ impl MessageUtils {
             }
         };
         log::info!("Incoming raw message from {}: {}", sender_tag, raw);
+        self.request_id = None;
         let data: Value = match serde_json::from_str(&raw) {
             Ok(v) => v,
             Err(e) => {
                 log::error!("JSON decode error: {}", e);
+                self.send_error(
+                    sender_tag,
+                    "error",
+                    ErrorCode::MalformedRequest,
+                    "malformed request: invalid JSON",
+                )
+                .await;
                 return;
             }
         };
         log::info!("Parsed JSON message from {}: {}", sender_tag, data);
-        if let Some(action) = data.get("action").and_then(Value::as_str) {
-            match action {
-                // Step 1: new user registration
-                "register" => self.handle_register(&data, sender_tag).await,
+        // Echo the client's requestId even when the rest of the request does not parse
+        self.request_id = data
+            .get("requestId")
+            .and_then(Value::as_str)
+            .map(str::to_string);
+        // Reject other protocol versions before their shape is interpreted. 'serverInfo'
+        // answers any version so that clients can find out what the server speaks.
+        let action = data.get("action").and_then(Value::as_str);
+        if let Some(version) = data.get("version")
+            && action != Some("serverInfo")
+            && version.as_u64() != Some(PROTOCOL_VERSION.into())
+        {
+            let response = match action {
+                Some(action) if ACTIONS.contains(&action) => format!("{}Response", action),
+                _ => "error".into(),
+            };
+            let message = format!(
+                "unsupported protocol version {}, server speaks {}",
+                version, PROTOCOL_VERSION
+            );
+            self.send_error(
+                sender_tag,
+                &response,
+                ErrorCode::UnsupportedVersion,
+                &message,
+            )
+            .await;
+            return;
+        }
+        let request = match SignedRequest::deserialize(&data) {
+            Ok(request) => request,
+            Err(e) => {
+                // Answer known actions on their usual response action so clients can match it
+                let (action, code, message) = match action {
+                    Some(action) if ACTIONS.contains(&action) => (
+                        format!("{}Response", action),
+                        ErrorCode::MalformedRequest,
+                        format!("malformed request: {}", e),
+                    ),
+                    Some(action) => (
+                        "error".into(),
+                        ErrorCode::UnknownAction,
+                        format!("unknown action: {}", action),
+                    ),
+                    None => (
+                        "error".into(),
+                        ErrorCode::MalformedRequest,
+                        "missing action".into(),
+                    ),
+                };
+                log::warn!("Rejected request from {}: {}", sender_tag, message);
+                self.send_error(sender_tag, &action, code, &message).await;
+                return;
+            }
+        };
+        // Unsigned requests precede any proof of identity
+        match request.request {
+            Request::ServerInfo => return self.handle_server_info(sender_tag).await,
+            Request::GetChallenge => return self.handle_get_challenge(sender_tag).await,
+            _ => {}
+        }
+        // Every other request is verified over its canonical form before any handler runs
+        let Some(requester) = self.authenticate(&request, &data, sender_tag).await else {
+            return;
+        };
+        self.sessions.touch(sender_tag);
+        let requester = requester.as_str();
+        match request.request {
+            // Step 1: new user registration
+            Request::Register {
+                username,
+                public_key,
+            } => {
+                self.handle_register(sender_tag, &username, &public_key)
+                    .await
+            }
+
+            // Step 2: approve pending registration (operators only)
+            Request::ApproveGroup { username } => {
+                self.handle_approve_group(sender_tag, requester, &username)
+                    .await
+            }
+
+            // Step 3: existing user connects by signing a challenge from 'getChallenge'
+            Request::ServerInfo | Request::GetChallenge => {}
+            Request::Connect { .. } => self.handle_connect(sender_tag, requester).await,
+            Request::Disconnect => self.handle_disconnect(sender_tag).await,
+
+            // Operators review and reject pending registrations
+            Request::ListPending { cursor, limit } => {
+                self.handle_list_pending(sender_tag, cursor.as_deref(), limit)
+                    .await
+            }
+            Request::RejectRegistration {
+                username,
+                cooldown_secs,
+            } => {
+                self.handle_reject_registration(sender_tag, requester, &username, cooldown_secs)
+                    .await
+            }
+
+            // Operators manage the set of operators
+            Request::AddOperator { name, public_key } => {
+                self.handle_add_operator(sender_tag, requester, &name, &public_key)
+                    .await
+            }
+            Request::RemoveOperator { name } => {
+                self.handle_remove_operator(sender_tag, requester, &name)
+                    .await
+            }
+            Request::RotateServerKey => self.handle_rotate_server_key(sender_tag, requester).await,
 
-                // Step 2: approve pending registration (admin only)
-                "approveGroup" => self.handle_approve_group(&data, sender_tag).await,
+            // Create a new group with the requester as its admin
+            Request::CreateGroup {
+                group_name,
+                is_public,
+                is_discoverable,
+                ..
+            } => {
+                self.handle_create_group(
+                    &data,
+                    sender_tag,
+                    requester,
+                    &group_name,
+                    is_public,
+                    is_discoverable,
+                )
+                .await
+            }
 
-                // Step 3: existing user connects
-                "connect" => self.handle_connect(&data, sender_tag).await,
+            // Moderators and above invite a user into a private group
+            Request::InviteGroup { group_id, username } => {
+                self.handle_invite_group(sender_tag, requester, &group_id, &username)
+                    .await
+            }
+            // Invited user accepts or declines the invite
+            Request::AcceptInvite { group_id } => {
+                self.handle_accept_invite(sender_tag, requester, &group_id)
+                    .await
+            }
+            Request::DeclineInvite { group_id } => {
+                self.handle_decline_invite(sender_tag, requester, &group_id)
+                    .await
+            }
+            // Self-service join for public groups, join requests for private ones
+            Request::JoinGroup { group_id } => {
+                self.handle_join_group(sender_tag, requester, &group_id)
+                    .await
+            }
+            Request::Knock { group_id } => {
+                self.handle_knock(sender_tag, requester, &group_id).await
+            }
+            Request::ListJoinRequests { group_id } => {
+                self.handle_list_join_requests(sender_tag, requester, &group_id)
+                    .await
+            }
+            // Leave a group, or remove someone ranked below the requester
+            Request::LeaveGroup { group_id } => {
+                self.handle_leave_group(sender_tag, requester, &group_id)
+                    .await
+            }
+            Request::KickMember { group_id, username } => {
+                self.handle_remove_member(sender_tag, requester, &group_id, &username, false)
+                    .await
+            }
+            Request::BanMember { group_id, username } => {
+                self.handle_remove_member(sender_tag, requester, &group_id, &username, true)
+                    .await
+            }
+            // Change a member's role (owner/admin only)
+            Request::SetRole {
+                group_id,
+                username,
+                role,
+            } => {
+                self.handle_set_role(sender_tag, requester, &group_id, &username, role)
+                    .await
+            }
+            // Browse groups flagged as discoverable
+            Request::ListGroups {
+                name_prefix,
+                cursor,
+                limit,
+            } => {
+                self.handle_list_groups(
+                    sender_tag,
+                    name_prefix.as_deref(),
+                    cursor.as_deref(),
+                    limit,
+                )
+                .await
+            }
 
-                // Step 4: client sends a group message (Redis Streams + push)
-                "sendGroup" => self.handle_send_group(&data, sender_tag).await,
-                // Step 5: client fetches new group messages (Redis Streams + pull)
-                "fetchGroup" => self.handle_fetch_group(&data, sender_tag).await,
-                _ => log::error!("Unknown action: {}", action),
+            // Step 4: client sends a message to one of its groups (message store + broker)
+            Request::SendGroup {
+                group_id,
+                ciphertext,
+            } => {
+                self.handle_send_group(sender_tag, requester, &group_id, &ciphertext)
+                    .await
+            }
+            // Step 5: client fetches new group messages (message store)
+            Request::FetchGroup {
+                group_id,
+                last_seen_id,
+            } => {
+                self.handle_fetch_group(sender_tag, requester, &group_id, &last_seen_id)
+                    .await
             }
         }
     }
 
-    /// Handle a client 'register': store their username + public key.
-    async fn handle_register(&mut self, data: &Value, sender_tag: AnonymousSenderTag) {
-        let username = match data.get("username").and_then(Value::as_str) {
-            Some(u) if !u.is_empty() => u,
-            _ => {
-                self.send_encapsulated_reply(
+    /// Verify a request's `signature` over its canonical JSON and check it against replay.
+    /// Returns the operator name for operator actions and the username otherwise; replies
+    /// with an error and returns None if the request is not authentic.
+    async fn authenticate(
+        &mut self,
+        request: &SignedRequest,
+        data: &Value,
+        sender_tag: AnonymousSenderTag,
+    ) -> Option<String> {
+        let response = format!("{}Response", request.request.action());
+        if request.signature.as_deref().is_none_or(str::is_empty) {
+            self.send_error(
+                sender_tag,
+                &response,
+                ErrorCode::BadSignature,
+                "missing or invalid signature",
+            )
+            .await;
+            return None;
+        }
+        let requester = if request.request.is_operator_action() {
+            let Some(operator) = self.verify_operator(data).await else {
+                self.send_error(
                     sender_tag,
-                    "error: missing or invalid username".into(),
-                    "registerResponse",
-                    None,
+                    &response,
+                    ErrorCode::Unauthorized,
+                    "unauthorized or bad signature",
                 )
                 .await;
-                return;
+                return None;
+            };
+            operator
+        } else {
+            // register, connect and createGroup name their user; everything else acts
+            // as the user this sender tag connected as
+            let username = match &request.request {
+                Request::Register { username, .. }
+                | Request::Connect { username, .. }
+                | Request::CreateGroup { username, .. } => username.clone(),
+                _ => {
+                    let Some(username) = self.sessions.username(sender_tag).map(str::to_string)
+                    else {
+                        self.send_error(
+                            sender_tag,
+                            &response,
+                            ErrorCode::NotConnected,
+                            "unknown user",
+                        )
+                        .await;
+                        return None;
+                    };
+                    username
+                }
+            };
+            // A registration is signed with the key it registers, which is not cached
+            // until an operator approves it
+            let registering = matches!(request.request, Request::Register { .. });
+            let public_key = match &request.request {
+                Request::Register { public_key, .. } => public_key.clone(),
+                _ => match self.db.get_user_by_username(&username).await {
+                    Ok(Some((_u, pk))) => pk,
+                    Ok(None) => {
+                        self.send_error(
+                            sender_tag,
+                            &response,
+                            ErrorCode::NotRegistered,
+                            "user not registered or not approved",
+                        )
+                        .await;
+                        return None;
+                    }
+                    Err(e) => {
+                        log::error!("DB error looking up {}: {}", username, e);
+                        self.send_error(
+                            sender_tag,
+                            &response,
+                            ErrorCode::Internal,
+                            "user lookup failed",
+                        )
+                        .await;
+                        return None;
+                    }
+                },
+            };
+            let verified = if registering {
+                self.crypto.verify_request(&public_key, data)
+            } else {
+                self.crypto
+                    .verify_cached_request(&username, &public_key, data)
+            };
+            if !verified {
+                self.send_error(
+                    sender_tag,
+                    &response,
+                    ErrorCode::BadSignature,
+                    "bad signature",
+                )
+                .await;
+                return None;
             }
+            username
         };
-        // The PGP public key (ASCII-armored) to register
-        let pubkey_armored = match data.get("publicKey").and_then(Value::as_str) {
-            Some(pk) if !pk.is_empty() => pk,
-            _ => {
-                self.send_encapsulated_reply(
+        // 'connect' answers a single-use challenge; every other request is timestamped
+        if let Request::Connect { nonce, .. } = &request.request {
+            if !self.replay.take_challenge(nonce, &sender_tag.to_string()) {
+                self.send_error(
                     sender_tag,
-                    "error: missing or invalid publicKey".into(),
-                    "registerResponse",
-                    None,
+                    &response,
+                    ErrorCode::InvalidChallenge,
+                    "unknown or expired challenge",
                 )
                 .await;
-                return;
+                return None;
             }
-        };
-        // Verify signature over the provided public key
-        let signature = match data.get("signature").and_then(Value::as_str) {
-            Some(sig) if !sig.is_empty() => sig,
-            _ => {
-                self.send_encapsulated_reply(
+        } else {
+            let Some(timestamp) = request.timestamp else {
+                self.send_error(
                     sender_tag,
-                    "error: missing or invalid signature".into(),
-                    "registerResponse",
-                    None,
+                    &response,
+                    ErrorCode::MalformedRequest,
+                    "missing or invalid timestamp",
                 )
                 .await;
-                return;
+                return None;
+            };
+            if !self
+                .check_replay(
+                    &requester,
+                    timestamp,
+                    &canonical_json(data),
+                    sender_tag,
+                    &response,
+                )
+                .await
+            {
+                return None;
             }
-        };
-        if !self
-            .crypto
-            .verify_pgp_signature(pubkey_armored, pubkey_armored, signature)
-        {
-            self.send_encapsulated_reply(
+        }
+        Some(requester)
+    }
+
+    /// Handle a client 'register': store their username + public key.
+    async fn handle_register(
+        &mut self,
+        sender_tag: AnonymousSenderTag,
+        username: &str,
+        public_key: &str,
+    ) {
+        // Rejected usernames may be on a cooldown before they can ask again
+        if let Ok(Some(until)) = self.db.get_registration_cooldown(username).await {
+            self.send_error(
                 sender_tag,
-                "error: bad signature".into(),
                 "registerResponse",
-                None,
+                ErrorCode::RegistrationCooldown,
+                &format!("registration on cooldown until {}", until),
             )
             .await;
             return;
         }
         // Record the pending join request
-        match self.db.add_pending_user(username, pubkey_armored).await {
+        match self.db.add_pending_user(username, public_key).await {
             Ok(true) => {
-                self.send_encapsulated_reply(
-                    sender_tag,
-                    "pending".into(),
-                    "registerResponse",
-                    None,
-                )
-                .await;
+                self.send_pending(sender_tag, "registerResponse").await;
             }
             Ok(false) => {
-                self.send_encapsulated_reply(
+                self.send_error(
                     sender_tag,
-                    "error: user already registered".into(),
                     "registerResponse",
-                    None,
+                    ErrorCode::AlreadyRegistered,
+                    "user already registered",
                 )
                 .await;
             }
             Err(e) => {
                 log::error!("DB error during register: {}", e);
-                self.send_encapsulated_reply(
+                self.send_error(
                     sender_tag,
-                    "error: registration failed".into(),
                     "registerResponse",
-                    None,
+                    ErrorCode::Internal,
+                    "registration failed",
                 )
                 .await;
             }
         }
     }
 
-    /// Handle a client 'approveGroup': verify admin signature and approve pending user.
-    async fn handle_approve_group(&mut self, data: &Value, sender_tag: AnonymousSenderTag) {
-        let username = match data.get("username").and_then(Value::as_str) {
-            Some(u) if !u.is_empty() => u,
-            _ => {
-                self.send_encapsulated_reply(
-                    sender_tag,
-                    "error: unauthorized or bad signature".into(),
-                    "approveGroupResponse",
-                    None,
-                )
-                .await;
-                return;
-            }
-        };
-        let signature = match data.get("signature").and_then(Value::as_str) {
-            Some(sig) if !sig.is_empty() => sig,
-            _ => {
-                self.send_encapsulated_reply(
-                    sender_tag,
-                    "error: unauthorized or bad signature".into(),
-                    "approveGroupResponse",
-                    None,
-                )
-                .await;
-                return;
-            }
-        };
-        let admin_key = env::var("ADMIN_PK").unwrap_or_default();
-        if admin_key.is_empty()
-            || !self
-                .crypto
-                .verify_pgp_signature(&admin_key, username, signature)
-        {
-            self.send_encapsulated_reply(
-                sender_tag,
-                "error: unauthorized or bad signature".into(),
-                "approveGroupResponse",
-                None,
-            )
-            .await;
-            return;
-        }
+    /// Handle an 'approveGroup': an operator approves a pending user.
+    async fn handle_approve_group(
+        &mut self,
+        sender_tag: AnonymousSenderTag,
+        operator: &str,
+        username: &str,
+    ) {
+        const ACTION: &str = "approveGroupResponse";
+        log::info!("approveGroup: operator {} approves {}", operator, username);
         // Fetch pending registration data
         let pubkey = match self.db.get_pending_user(username).await {
             Ok(Some(pk)) => pk,
-            _ => {
-                self.send_encapsulated_reply(
+            Ok(None) => {
+                self.send_error(
                     sender_tag,
-                    "error: approve failed".into(),
-                    "approveGroupResponse",
-                    None,
+                    ACTION,
+                    ErrorCode::NoSuchRegistration,
+                    "no such pending registration",
                 )
                 .await;
                 return;
             }
+            Err(e) => {
+                log::error!("DB error during approveGroup: {}", e);
+                self.send_error(sender_tag, ACTION, ErrorCode::Internal, "approve failed")
+                    .await;
+                return;
+            }
         };
         // Approve user: add to users table
         match self.db.add_user(username, &pubkey).await {
             Ok(true) => {
+                self.crypto.forget_key(username);
                 let _ = self.db.remove_pending_user(username).await;
-                self.send_encapsulated_reply(
-                    sender_tag,
-                    "success".into(),
-                    "approveGroupResponse",
-                    None,
-                )
-                .await;
+                self.send_encapsulated_reply(sender_tag, "success".into(), ACTION)
+                    .await;
             }
-            _ => {
-                self.send_encapsulated_reply(
+            Ok(false) => {
+                self.send_error(
                     sender_tag,
-                    "error: approve failed".into(),
-                    "approveGroupResponse",
-                    None,
+                    ACTION,
+                    ErrorCode::AlreadyRegistered,
+                    "user already registered",
                 )
                 .await;
             }
+            Err(e) => {
+                log::error!("DB error during approveGroup: {}", e);
+                self.send_error(sender_tag, ACTION, ErrorCode::Internal, "approve failed")
+                    .await;
+            }
         }
     }
 
-    /// Handle a client 'connect': verify signature, authenticate, and subscribe to group channel.
-    async fn handle_connect(&mut self, data: &Value, sender_tag: AnonymousSenderTag) {
-        let username = match data.get("username").and_then(Value::as_str) {
-            Some(u) if !u.is_empty() => u,
-            _ => {
-                self.send_encapsulated_reply(
+    /// Handle a client 'serverInfo': describe the protocol version, actions and limits
+    /// and hand out the key replies are signed with.
+    async fn handle_server_info(&mut self, sender_tag: AnonymousSenderTag) {
+        let public_key = match self.crypto.load_public_key() {
+            Ok(key) => key,
+            Err(e) => {
+                log::error!("Failed to load server public key: {}", e);
+                self.send_error(
                     sender_tag,
-                    "error: missing or invalid username".into(),
-                    "connectResponse",
-                    None,
+                    "serverInfoResponse",
+                    ErrorCode::Internal,
+                    "server info unavailable",
                 )
                 .await;
                 return;
             }
         };
-        let signature = match data.get("signature").and_then(Value::as_str) {
-            Some(sig) if !sig.is_empty() => sig,
-            _ => {
-                self.send_encapsulated_reply(
-                    sender_tag,
-                    "error: missing or invalid signature".into(),
-                    "connectResponse",
-                    None,
-                )
-                .await;
-                return;
+        let content = Response::ServerInfo(ServerInfo {
+            version: PROTOCOL_VERSION,
+            actions: ACTIONS.iter().map(|a| a.to_string()).collect(),
+            limits: Limits {
+                max_message_bytes: MAX_MESSAGE_BYTES,
+                fetch_batch_size: FETCH_BATCH_SIZE,
+                max_page_size: LIST_GROUPS_MAX_LIMIT.min(LIST_PENDING_MAX_LIMIT),
+                session_idle_secs: self.sessions.idle_timeout().as_secs(),
+            },
+            public_key,
+        })
+        .content();
+        self.announce_key_rotation(sender_tag).await;
+        self.send_encapsulated_reply(sender_tag, content, "serverInfoResponse")
+            .await;
+    }
+
+    /// Handle a client 'getChallenge': issue a single-use nonce for the next 'connect'.
+    async fn handle_get_challenge(&mut self, sender_tag: AnonymousSenderTag) {
+        let nonce = self.replay.issue_challenge(&sender_tag.to_string());
+        let content = Response::Challenge(Challenge { nonce }).content();
+        self.announce_key_rotation(sender_tag).await;
+        self.send_encapsulated_reply(sender_tag, content, "getChallengeResponse")
+            .await;
+    }
+
+    /// Handle a client 'connect': open a session binding the sender tag to the user and
+    /// subscribe it to the user's group channels.
+    async fn handle_connect(&mut self, sender_tag: AnonymousSenderTag, username: &str) {
+        // Connecting again replaces the tag's session and its subscriptions
+        self.end_session(sender_tag, "replaced by a new connect");
+        let session_id = self.sessions.open(sender_tag, username);
+        log::info!(
+            "session {} opened for {} on {}",
+            session_id,
+            username,
+            sender_tag
+        );
+        self.announce_key_rotation(sender_tag).await;
+        // Send success response
+        self.send_encapsulated_reply(sender_tag, "success".into(), "connectResponse")
+            .await;
+        // Subscribe to the channel of every group the user belongs to
+        let groups = match self.db.get_groups_for_user(username).await {
+            Ok(groups) => groups,
+            Err(e) => {
+                log::error!("DB error fetching groups for {}: {}", username, e);
+                Vec::new()
             }
         };
-        // Verify user is approved and retrieve their public key
-        let public_key = match self.db.get_user_by_username(username).await {
-            Ok(Some((_u, pubkey))) => pubkey,
-            _ => {
-                self.send_encapsulated_reply(
+        for group_id in groups {
+            self.subscribe_to_group(sender_tag, &group_id).await;
+        }
+    }
+
+    /// Handle a client 'disconnect': end the session and stop its live delivery.
+    async fn handle_disconnect(&mut self, sender_tag: AnonymousSenderTag) {
+        self.end_session(sender_tag, "disconnected");
+        self.send_encapsulated_reply(sender_tag, "success".into(), "disconnectResponse")
+            .await;
+    }
+
+    /// Handle a 'listPending': an operator pages through registrations awaiting approval.
+    async fn handle_list_pending(
+        &mut self,
+        sender_tag: AnonymousSenderTag,
+        cursor: Option<&str>,
+        limit: Option<u32>,
+    ) {
+        const ACTION: &str = "listPendingResponse";
+        let cursor = cursor.filter(|c| !c.is_empty());
+        let limit = limit
+            .unwrap_or(LIST_PENDING_DEFAULT_LIMIT)
+            .clamp(1, LIST_PENDING_MAX_LIMIT);
+        match self.db.list_pending_users(cursor, limit).await {
+            Ok(rows) => {
+                let next_cursor = if rows.len() == limit as usize {
+                    rows.last().map(|(username, _, _)| username.clone())
+                } else {
+                    None
+                };
+                let pending = rows
+                    .into_iter()
+                    .map(|(username, public_key, requested_at)| PendingUser {
+                        fingerprint: self.crypto.fingerprint(&public_key),
+                        username,
+                        requested_at,
+                    })
+                    .collect();
+                let content = Response::PendingPage(PendingPage {
+                    pending,
+                    next_cursor,
+                })
+                .content();
+                self.send_encapsulated_reply(sender_tag, content, ACTION)
+                    .await;
+            }
+            Err(e) => {
+                log::error!("DB error during listPending: {}", e);
+                self.send_error(
                     sender_tag,
-                    "error: user not registered or not approved".into(),
-                    "connectResponse",
-                    None,
+                    ACTION,
+                    ErrorCode::Internal,
+                    "list pending failed",
                 )
                 .await;
-                return;
             }
-        };
-        // Verify detached signature over the username
-        if !self
-            .crypto
-            .verify_pgp_signature(&public_key, username, signature)
-        {
-            self.send_encapsulated_reply(
-                sender_tag,
-                "error: bad signature".into(),
-                "connectResponse",
-                None,
-            )
-            .await;
-            return;
         }
-        // Mark sender as an active client
-        self.active_clients.insert(sender_tag, username.to_string());
-        // Send success response
-        self.send_encapsulated_reply(sender_tag, "success".into(), "connectResponse", None)
-            .await;
-        // Subscribe to the single group channel for incoming messages
-        let tag_str = sender_tag.to_string();
-        let channel = "group:channel";
-        let my_tag = tag_str.clone();
-        let mixnet_sender = self.sender.clone();
-        let client = self.redis_client.clone();
-        tokio::spawn(async move {
-            if let Ok(conn) = client.get_async_connection().await {
-                let mut pubsub = conn.into_pubsub();
-                let _ = pubsub.subscribe(channel).await;
-                let mut on_message = pubsub.on_message();
-                while let Some(msg) = on_message.next().await {
-                    if let Ok(payload) = msg.get_payload::<String>() {
-                        if let Ok(tag) = AnonymousSenderTag::try_from_base58_string(&my_tag) {
-                            let _ = mixnet_sender.send_reply(tag, payload).await;
-                        }
+    }
+
+    /// Handle a 'rejectRegistration': an operator drops a pending registration and may
+    /// block the username from registering again for `cooldownSecs`.
+    async fn handle_reject_registration(
+        &mut self,
+        sender_tag: AnonymousSenderTag,
+        operator: &str,
+        username: &str,
+        cooldown_secs: Option<i64>,
+    ) {
+        const ACTION: &str = "rejectRegistrationResponse";
+        match self.db.remove_pending_user(username).await {
+            Ok(true) => {
+                log::info!(
+                    "rejectRegistration: operator {} rejects {}",
+                    operator,
+                    username
+                );
+                if let Some(secs) = cooldown_secs.filter(|secs| *secs > 0) {
+                    let until = chrono::Utc::now().timestamp().saturating_add(secs);
+                    if let Err(e) = self.db.set_registration_cooldown(username, until).await {
+                        log::error!("DB error setting cooldown for {}: {}", username, e);
                     }
                 }
+                self.send_encapsulated_reply(sender_tag, "success".into(), ACTION)
+                    .await;
+            }
+            Ok(false) => {
+                self.send_error(
+                    sender_tag,
+                    ACTION,
+                    ErrorCode::NoSuchRegistration,
+                    "no such pending registration",
+                )
+                .await;
+            }
+            Err(e) => {
+                log::error!("DB error during rejectRegistration: {}", e);
+                self.send_error(sender_tag, ACTION, ErrorCode::Internal, "reject failed")
+                    .await;
             }
-        });
+        }
     }
 
-    async fn handle_send_group(&mut self, data: &Value, sender_tag: AnonymousSenderTag) {
-        let ciphertext = data.get("ciphertext").and_then(Value::as_str);
-        if ciphertext.is_none() {
-            self.send_encapsulated_reply(
-                sender_tag,
-                "error: missing ciphertext".into(),
-                "sendGroupResponse",
-                None,
-            )
-            .await;
-            return;
-        }
-        let ciphertext = ciphertext.unwrap();
-        let username = match self.active_clients.get(&sender_tag) {
-            Some(u) => u.clone(),
-            None => {
-                self.send_encapsulated_reply(
+    /// Handle an 'addOperator': an existing operator signs the new operator's public key.
+    async fn handle_add_operator(
+        &mut self,
+        sender_tag: AnonymousSenderTag,
+        operator: &str,
+        name: &str,
+        public_key: &str,
+    ) {
+        const ACTION: &str = "addOperatorResponse";
+        match self.db.add_operator(name, public_key).await {
+            Ok(true) => {
+                log::info!("addOperator: {} added operator {}", operator, name);
+                self.send_encapsulated_reply(sender_tag, "success".into(), ACTION)
+                    .await;
+            }
+            Ok(false) => {
+                self.send_error(
                     sender_tag,
-                    "error: unknown user".into(),
-                    "sendGroupResponse",
-                    None,
+                    ACTION,
+                    ErrorCode::OperatorExists,
+                    "operator already exists",
                 )
                 .await;
-                return;
             }
-        };
-        // push the encrypted message into Redis Stream for pull-based fan-out
-        let stream_key = "group:stream";
-        let payload = json!({
-            "sender": username,
-            "ciphertext": ciphertext
-        })
-        .to_string();
-        if let Ok(mut conn) = self.redis_client.get_async_connection().await {
-            // XADD <stream_key> * message <payload>
-            let _: Result<String, _> = conn
-                .xadd(&stream_key, "*", &[("message", payload.as_str())])
+            Err(e) => {
+                log::error!("DB error during addOperator: {}", e);
+                self.send_error(
+                    sender_tag,
+                    ACTION,
+                    ErrorCode::Internal,
+                    "add operator failed",
+                )
                 .await;
+            }
         }
-        self.send_encapsulated_reply(sender_tag, "success".into(), "sendGroupResponse", None)
-            .await;
     }
 
-    /// Handle a client request to fetch new group messages (Redis Streams + pull)
-    async fn handle_fetch_group(&mut self, data: &Value, sender_tag: AnonymousSenderTag) {
-        // Extract and verify signature over lastSeenId
-        let last_seen = match data.get("lastSeenId").and_then(Value::as_str) {
-            Some(s) if !s.is_empty() => s,
-            _ => {
-                self.send_encapsulated_reply(
+    /// Handle a 'removeOperator': an operator signs the name of the operator to remove.
+    async fn handle_remove_operator(
+        &mut self,
+        sender_tag: AnonymousSenderTag,
+        operator: &str,
+        name: &str,
+    ) {
+        const ACTION: &str = "removeOperatorResponse";
+        match self.db.get_operators().await {
+            Ok(ops) if !ops.iter().any(|(op, _)| op == name) => {
+                self.send_error(
                     sender_tag,
-                    "error: missing or invalid lastSeenId".into(),
-                    "fetchGroupResponse",
-                    None,
+                    ACTION,
+                    ErrorCode::NoSuchOperator,
+                    "no such operator",
                 )
                 .await;
                 return;
             }
-        };
-        let signature = match data.get("signature").and_then(Value::as_str) {
-            Some(sig) if !sig.is_empty() => sig,
-            _ => {
-                self.send_encapsulated_reply(
+            // Never lock the server out of registration approval
+            Ok(ops) if ops.len() <= 1 => {
+                self.send_error(
                     sender_tag,
-                    "error: missing or invalid signature".into(),
-                    "fetchGroupResponse",
-                    None,
+                    ACTION,
+                    ErrorCode::LastOperator,
+                    "cannot remove the last operator",
                 )
                 .await;
                 return;
             }
-        };
-        // Verify signature against registered public key
-        let username = match self.active_clients.get(&sender_tag) {
-            Some(u) => u.clone(),
-            None => {
-                self.send_encapsulated_reply(
+            Ok(_) => {}
+            Err(e) => {
+                log::error!("DB error during removeOperator: {}", e);
+                self.send_error(
                     sender_tag,
-                    "error: user not registered or not approved".into(),
-                    "fetchGroupResponse",
-                    None,
+                    ACTION,
+                    ErrorCode::Internal,
+                    "remove operator failed",
                 )
                 .await;
                 return;
             }
-        };
-        let public_key = match self.db.get_user_by_username(&username).await {
-            Ok(Some((_u, pk))) => pk,
-            _ => {
-                self.send_encapsulated_reply(
+        }
+        match self.db.remove_operator(name).await {
+            Ok(true) => {
+                self.crypto.forget_key(&operator_cache_key(name));
+                log::info!("removeOperator: {} removed operator {}", operator, name);
+                self.send_encapsulated_reply(sender_tag, "success".into(), ACTION)
+                    .await;
+            }
+            Ok(false) => {
+                self.send_error(
                     sender_tag,
-                    "error: user not registered or not approved".into(),
-                    "fetchGroupResponse",
-                    None,
+                    ACTION,
+                    ErrorCode::NoSuchOperator,
+                    "no such operator",
+                )
+                .await;
+            }
+            Err(e) => {
+                log::error!("DB error during removeOperator: {}", e);
+                self.send_error(
+                    sender_tag,
+                    ACTION,
+                    ErrorCode::Internal,
+                    "remove operator failed",
+                )
+                .await;
+            }
+        }
+    }
+
+    /// Handle a 'rotateServerKey': an operator replaces the server signing key. Every
+    /// connected session is told about the new key in a notice signed with the old one.
+    async fn handle_rotate_server_key(&mut self, sender_tag: AnonymousSenderTag, operator: &str) {
+        const ACTION: &str = "rotateServerKeyResponse";
+        // Another rotation now would overwrite the previous key clients may still pin
+        if let Some(rotation) = self.crypto.key_rotation() {
+            self.send_error(
+                sender_tag,
+                ACTION,
+                ErrorCode::RotationInProgress,
+                &format!(
+                    "the last key rotation's grace period lasts until {}",
+                    rotation.grace_until
+                ),
+            )
+            .await;
+            return;
+        }
+        let rotation = match self.crypto.rotate_key(KEY_ROTATION_GRACE) {
+            Ok(rotation) => rotation,
+            Err(e) => {
+                log::error!("Server key rotation failed: {:#}", e);
+                self.send_error(
+                    sender_tag,
+                    ACTION,
+                    ErrorCode::Internal,
+                    "key rotation failed",
                 )
                 .await;
                 return;
             }
         };
-        if !self
-            .crypto
-            .verify_pgp_signature(&public_key, last_seen, signature)
+        log::info!("rotateServerKey: {} rotated the server key", operator);
+        // The operator may not be connected, and needs the notice to trust the reply
+        self.send_rotation_notice(sender_tag, &rotation).await;
+        for session in self.sessions.tags() {
+            if session == sender_tag {
+                continue;
+            }
+            self.send_rotation_notice(session, &rotation).await;
+        }
+        self.send_encapsulated_reply(sender_tag, "success".into(), ACTION)
+            .await;
+    }
+
+    /// Handle a client 'createGroup': check the creation policy, create the group and add
+    /// the requester as its first member.
+    async fn handle_create_group(
+        &mut self,
+        data: &Value,
+        sender_tag: AnonymousSenderTag,
+        username: &str,
+        group_name: &str,
+        is_public: bool,
+        is_discoverable: bool,
+    ) {
+        if self.group_creation_policy == GroupCreationPolicy::OperatorsOnly
+            && self.verify_operator(data).await.is_none()
         {
-            self.send_encapsulated_reply(
+            self.send_error(
                 sender_tag,
-                "error: bad signature".into(),
-                "fetchGroupResponse",
-                None,
+                "createGroupResponse",
+                ErrorCode::Unauthorized,
+                "unauthorized",
             )
             .await;
             return;
         }
-        // Read new entries from the Redis Stream for the single group
-        let stream_key = "group:stream";
-        let mut msgs = Vec::new();
-        if let Ok(mut conn) = self.redis_client.get_async_connection().await {
-            // Non-blocking XREAD from last_seen
-            if let Ok(reply) = conn
-                .xread::<_, _, Vec<redis::streams::StreamReadReply>>(&[&stream_key], &[last_seen])
-                .await
-            {
-                for stream in reply {
-                    for sk in stream.keys {
-                        for entry in sk.ids {
-                            // entry.id is the message ID
-                            // entry.map contains field-value pairs
-                            if let Some(redis::Value::Data(bytes)) = entry.map.get("message") {
-                                if let Ok(s) = String::from_utf8(bytes.clone()) {
-                                    msgs.push((s, entry.id.clone()));
-                                }
-                            }
-                        }
-                    }
+        let group_id = Uuid::new_v4().to_string();
+        match self
+            .db
+            .create_group_with_owner(&group_id, group_name, username, is_public, is_discoverable)
+            .await
+        {
+            Ok(true) => {
+                let content = Response::GroupCreated(GroupCreated {
+                    group_id: group_id.clone(),
+                })
+                .content();
+                self.send_encapsulated_reply(sender_tag, content, "createGroupResponse")
+                    .await;
+                // A connected creator starts receiving the new group's messages right away
+                if self.sessions.username(sender_tag) == Some(username) {
+                    self.subscribe_to_group(sender_tag, &group_id).await;
+                }
+            }
+            other => {
+                if let Err(e) = other {
+                    log::error!("DB error during createGroup: {}", e);
                 }
+                self.send_error(
+                    sender_tag,
+                    "createGroupResponse",
+                    ErrorCode::Internal,
+                    "create group failed",
+                )
+                .await;
             }
         }
-        // Send back all new messages
-        let content = json!({
-            "messages": msgs    // Vec<(ciphertext, messageId)>
-        })
-        .to_string();
-        self.send_encapsulated_reply(sender_tag, content, "fetchGroupResponse", None)
-            .await;
     }
 
-    /// Sign and send a JSON reply over the mixnet using SURBs.
-    async fn send_encapsulated_reply(
-        &self,
-        recipient: AnonymousSenderTag,
-        content: String,
-        action: &str,
-        context: Option<&str>,
+    /// Handle a client 'inviteGroup': a member with invite permission invites a registered user.
+    async fn handle_invite_group(
+        &mut self,
+        sender_tag: AnonymousSenderTag,
+        admin: &str,
+        group_id: &str,
+        invitee: &str,
     ) {
-        let mut payload = json!({"action": action, "content": content});
-        if let Some(ctx) = context {
-            payload["context"] = json!(ctx);
-        }
-        let to_sign = payload["content"].as_str().unwrap_or_default().to_string();
-        if let Ok(signature) = self.crypto.sign_message(&self.client_id, &to_sign) {
-            payload["signature"] = json!(signature);
-            let msg = payload.to_string();
-            let _ = self.sender.send_reply(recipient, msg).await;
-        } else {
-            log::error!("sendEncapsulatedReply - failed to sign message");
+        const ACTION: &str = "inviteGroupResponse";
+        if self
+            .authorize(group_id, admin, Permission::Invite, sender_tag, ACTION)
+            .await
+            .is_none()
+        {
+            return;
         }
-    }
+        if !matches!(self.db.get_user_by_username(invitee).await, Ok(Some(_))) {
+            self.send_error(
+                sender_tag,
+                ACTION,
+                ErrorCode::NotRegistered,
+                "user not registered or not approved",
+            )
+            .await;
+            return;
+        }
+        if self.is_banned(group_id, invitee).await {
+            self.send_error(sender_tag, ACTION, ErrorCode::Banned, "user is banned")
+                .await;
+            return;
+        }
+        if self.is_member(group_id, invitee).await {
+            self.send_error(
+                sender_tag,
+                ACTION,
+                ErrorCode::AlreadyMember,
+                "user already a member",
+            )
+            .await;
+            return;
+        }
+        // An invite answering a pending join request admits the user directly
+        if matches!(
+            self.db.remove_join_request(group_id, invitee).await,
+            Ok(true)
+        ) {
+            match self.db.add_group_member(group_id, invitee).await {
+                Ok(true) => {
+                    self.send_encapsulated_reply(sender_tag, "success".into(), ACTION)
+                        .await;
+                    let notice = Response::GroupJoined(GroupJoined {
+                        group_id: group_id.to_string(),
+                        approved_by: admin.to_string(),
+                    })
+                    .content();
+                    for tag in self.sessions_for(invitee) {
+                        self.send_notice(tag, notice.clone(), "groupJoined").await;
+                        self.subscribe_to_group(tag, group_id).await;
+                    }
+                }
+                other => {
+                    if let Err(e) = other {
+                        log::error!("DB error admitting {} to {}: {}", invitee, group_id, e);
+                    }
+                    self.send_error(sender_tag, ACTION, ErrorCode::Internal, "invite failed")
+                        .await;
+                }
+            }
+            return;
+        }
+        if matches!(self.db.is_user_invited(group_id, invitee).await, Ok(true)) {
+            self.send_error(
+                sender_tag,
+                ACTION,
+                ErrorCode::AlreadyInvited,
+                "user already invited",
+            )
+            .await;
+            return;
+        }
+        match self.db.add_group_invite(group_id, invitee).await {
+            Ok(true) => {
+                self.send_encapsulated_reply(sender_tag, "success".into(), ACTION)
+                    .await;
+                // Let the invitee know right away if they are connected
+                let notice = Response::GroupInvite(GroupInvite {
+                    group_id: group_id.to_string(),
+                    invited_by: admin.to_string(),
+                })
+                .content();
+                for tag in self.sessions_for(invitee) {
+                    self.send_notice(tag, notice.clone(), "groupInvite").await;
+                }
+            }
+            other => {
+                if let Err(e) = other {
+                    log::error!("DB error during inviteGroup: {}", e);
+                }
+                self.send_error(sender_tag, ACTION, ErrorCode::Internal, "invite failed")
+                    .await;
+            }
+        }
+    }
+
+    /// Handle a client 'acceptInvite': move the user from the invite list into the group.
+    async fn handle_accept_invite(
+        &mut self,
+        sender_tag: AnonymousSenderTag,
+        username: &str,
+        group_id: &str,
+    ) {
+        const ACTION: &str = "acceptInviteResponse";
+        match self.db.accept_group_invite(group_id, username).await {
+            Ok(true) => {
+                self.send_encapsulated_reply(sender_tag, "success".into(), ACTION)
+                    .await;
+                self.subscribe_to_group(sender_tag, group_id).await;
+            }
+            Ok(false) => {
+                self.send_error(
+                    sender_tag,
+                    ACTION,
+                    ErrorCode::NoSuchInvite,
+                    "no such invite",
+                )
+                .await;
+            }
+            Err(e) => {
+                log::error!("DB error during acceptInvite: {}", e);
+                self.send_error(sender_tag, ACTION, ErrorCode::Internal, "accept failed")
+                    .await;
+            }
+        }
+    }
+
+    /// Handle a client 'declineInvite': drop the pending invite.
+    async fn handle_decline_invite(
+        &mut self,
+        sender_tag: AnonymousSenderTag,
+        username: &str,
+        group_id: &str,
+    ) {
+        const ACTION: &str = "declineInviteResponse";
+        match self.db.remove_group_invite(group_id, username).await {
+            Ok(true) => {
+                self.send_encapsulated_reply(sender_tag, "success".into(), ACTION)
+                    .await;
+            }
+            Ok(false) => {
+                self.send_error(
+                    sender_tag,
+                    ACTION,
+                    ErrorCode::NoSuchInvite,
+                    "no such invite",
+                )
+                .await;
+            }
+            Err(e) => {
+                log::error!("DB error during declineInvite: {}", e);
+                self.send_error(sender_tag, ACTION, ErrorCode::Internal, "decline failed")
+                    .await;
+            }
+        }
+    }
+
+    /// Handle a client 'joinGroup': any approved user may join a public group.
+    async fn handle_join_group(
+        &mut self,
+        sender_tag: AnonymousSenderTag,
+        username: &str,
+        group_id: &str,
+    ) {
+        const ACTION: &str = "joinGroupResponse";
+        match self.db.is_group_public(group_id).await {
+            Ok(Some(true)) => {}
+            Ok(Some(false)) => {
+                self.send_error(
+                    sender_tag,
+                    ACTION,
+                    ErrorCode::GroupPrivate,
+                    "group is private",
+                )
+                .await;
+                return;
+            }
+            Ok(None) => {
+                self.send_error(sender_tag, ACTION, ErrorCode::NoSuchGroup, "no such group")
+                    .await;
+                return;
+            }
+            Err(e) => {
+                log::error!("DB error during joinGroup: {}", e);
+                self.send_error(sender_tag, ACTION, ErrorCode::Internal, "join failed")
+                    .await;
+                return;
+            }
+        }
+        if self.is_banned(group_id, username).await {
+            self.send_error(sender_tag, ACTION, ErrorCode::Banned, "banned")
+                .await;
+            return;
+        }
+        if self.is_member(group_id, username).await {
+            self.send_error(
+                sender_tag,
+                ACTION,
+                ErrorCode::AlreadyMember,
+                "user already a member",
+            )
+            .await;
+            return;
+        }
+        match self.db.add_group_member(group_id, username).await {
+            Ok(true) => {
+                // Joining supersedes any outstanding invite or join request
+                let _ = self.db.remove_group_invite(group_id, username).await;
+                let _ = self.db.remove_join_request(group_id, username).await;
+                self.send_encapsulated_reply(sender_tag, "success".into(), ACTION)
+                    .await;
+                self.subscribe_to_group(sender_tag, group_id).await;
+            }
+            other => {
+                if let Err(e) = other {
+                    log::error!("DB error during joinGroup: {}", e);
+                }
+                self.send_error(sender_tag, ACTION, ErrorCode::Internal, "join failed")
+                    .await;
+            }
+        }
+    }
+
+    /// Handle a client 'knock': file a join request for a private group with its moderators.
+    async fn handle_knock(
+        &mut self,
+        sender_tag: AnonymousSenderTag,
+        username: &str,
+        group_id: &str,
+    ) {
+        const ACTION: &str = "knockResponse";
+        match self.db.is_group_public(group_id).await {
+            Ok(Some(false)) => {}
+            Ok(Some(true)) => {
+                self.send_error(
+                    sender_tag,
+                    ACTION,
+                    ErrorCode::GroupPublic,
+                    "group is public, use joinGroup",
+                )
+                .await;
+                return;
+            }
+            Ok(None) => {
+                self.send_error(sender_tag, ACTION, ErrorCode::NoSuchGroup, "no such group")
+                    .await;
+                return;
+            }
+            Err(e) => {
+                log::error!("DB error during knock: {}", e);
+                self.send_error(sender_tag, ACTION, ErrorCode::Internal, "knock failed")
+                    .await;
+                return;
+            }
+        }
+        if self.is_banned(group_id, username).await {
+            self.send_error(sender_tag, ACTION, ErrorCode::Banned, "banned")
+                .await;
+            return;
+        }
+        if self.is_member(group_id, username).await {
+            self.send_error(
+                sender_tag,
+                ACTION,
+                ErrorCode::AlreadyMember,
+                "user already a member",
+            )
+            .await;
+            return;
+        }
+        match self.db.add_join_request(group_id, username).await {
+            Ok(true) => {
+                self.send_pending(sender_tag, ACTION).await;
+                // Surface the request to connected members who can answer it
+                let notice = Response::JoinRequest(JoinRequest {
+                    group_id: group_id.to_string(),
+                    username: username.to_string(),
+                })
+                .content();
+                let approvers = self
+                    .db
+                    .get_group_members_with_roles(group_id)
+                    .await
+                    .unwrap_or_default();
+                for (approver, _) in approvers
+                    .into_iter()
+                    .filter(|(_, role)| role.allows(Permission::Invite))
+                {
+                    for tag in self.sessions_for(&approver) {
+                        self.send_notice(tag, notice.clone(), "joinRequest").await;
+                    }
+                }
+            }
+            Ok(false) => {
+                self.send_error(
+                    sender_tag,
+                    ACTION,
+                    ErrorCode::JoinRequestPending,
+                    "join request already pending",
+                )
+                .await;
+            }
+            Err(e) => {
+                log::error!("DB error during knock: {}", e);
+                self.send_error(sender_tag, ACTION, ErrorCode::Internal, "knock failed")
+                    .await;
+            }
+        }
+    }
+
+    /// Handle a client 'listJoinRequests': a member with invite permission lists pending
+    /// join requests.
+    async fn handle_list_join_requests(
+        &mut self,
+        sender_tag: AnonymousSenderTag,
+        admin: &str,
+        group_id: &str,
+    ) {
+        const ACTION: &str = "listJoinRequestsResponse";
+        if self
+            .authorize(group_id, admin, Permission::Invite, sender_tag, ACTION)
+            .await
+            .is_none()
+        {
+            return;
+        }
+        match self.db.get_join_requests(group_id).await {
+            Ok(requests) => {
+                let content = Response::JoinRequests(JoinRequests {
+                    group_id: group_id.to_string(),
+                    requests,
+                })
+                .content();
+                self.send_encapsulated_reply(sender_tag, content, ACTION)
+                    .await;
+            }
+            Err(e) => {
+                log::error!("DB error during listJoinRequests: {}", e);
+                self.send_error(
+                    sender_tag,
+                    ACTION,
+                    ErrorCode::Internal,
+                    "list join requests failed",
+                )
+                .await;
+            }
+        }
+    }
+
+    /// Handle a client 'leaveGroup': the requester leaves one of their groups.
+    async fn handle_leave_group(
+        &mut self,
+        sender_tag: AnonymousSenderTag,
+        username: &str,
+        group_id: &str,
+    ) {
+        const ACTION: &str = "leaveGroupResponse";
+        if matches!(
+            self.db.get_member_role(group_id, username).await,
+            Ok(Some(Role::Owner))
+        ) {
+            self.send_error(
+                sender_tag,
+                ACTION,
+                ErrorCode::OwnerCannotLeave,
+                "owner cannot leave the group",
+            )
+            .await;
+            return;
+        }
+        match self.db.remove_group_member(group_id, username).await {
+            Ok(true) => {
+                self.unsubscribe_user_from_group(username, group_id);
+                self.send_encapsulated_reply(sender_tag, "success".into(), ACTION)
+                    .await;
+            }
+            Ok(false) => {
+                self.send_error(
+                    sender_tag,
+                    ACTION,
+                    ErrorCode::NotMember,
+                    "not a member of this group",
+                )
+                .await;
+            }
+            Err(e) => {
+                log::error!("DB error during leaveGroup: {}", e);
+                self.send_error(sender_tag, ACTION, ErrorCode::Internal, "leave failed")
+                    .await;
+            }
+        }
+    }
+
+    /// Handle a client 'kickMember' or 'banMember': a moderator (kick) or admin (ban) removes a
+    /// lower-ranked member, optionally adding them to the group's ban list.
+    async fn handle_remove_member(
+        &mut self,
+        sender_tag: AnonymousSenderTag,
+        admin: &str,
+        group_id: &str,
+        target: &str,
+        ban: bool,
+    ) {
+        let action = if ban {
+            "banMemberResponse"
+        } else {
+            "kickMemberResponse"
+        };
+        let permission = if ban {
+            Permission::Ban
+        } else {
+            Permission::Kick
+        };
+        let Some(actor_role) = self
+            .authorize(group_id, admin, permission, sender_tag, action)
+            .await
+        else {
+            return;
+        };
+        // Only members of a strictly lower role can be removed
+        if !matches!(
+            self.db.get_member_role(group_id, target).await,
+            Ok(role) if role.is_none_or(|r| r < actor_role)
+        ) {
+            self.send_error(sender_tag, action, ErrorCode::Unauthorized, "unauthorized")
+                .await;
+            return;
+        }
+        let removed = if ban {
+            self.db.ban_user(group_id, target).await
+        } else {
+            self.db.remove_group_member(group_id, target).await
+        };
+        match removed {
+            Ok(true) => {
+                // Cut off live delivery before acknowledging the removal
+                self.unsubscribe_user_from_group(target, group_id);
+                self.send_encapsulated_reply(sender_tag, "success".into(), action)
+                    .await;
+                let reason = if ban { "banned" } else { "kicked" };
+                let notice = Response::GroupRemoved(GroupRemoved {
+                    group_id: group_id.to_string(),
+                    reason: reason.to_string(),
+                })
+                .content();
+                for tag in self.sessions_for(target) {
+                    self.send_notice(tag, notice.clone(), "groupRemoved").await;
+                }
+            }
+            Ok(false) => {
+                let (code, message) = if ban {
+                    (ErrorCode::AlreadyBanned, "user already banned")
+                } else {
+                    (ErrorCode::NotMember, "not a member of this group")
+                };
+                self.send_error(sender_tag, action, code, message).await;
+            }
+            Err(e) => {
+                log::error!("DB error during {}: {}", action, e);
+                self.send_error(
+                    sender_tag,
+                    action,
+                    ErrorCode::Internal,
+                    "remove member failed",
+                )
+                .await;
+            }
+        }
+    }
+
+    /// Handle a client 'setRole': promote or demote a member below the requester's own role.
+    async fn handle_set_role(
+        &mut self,
+        sender_tag: AnonymousSenderTag,
+        actor: &str,
+        group_id: &str,
+        target: &str,
+        new_role: Role,
+    ) {
+        const ACTION: &str = "setRoleResponse";
+        let Some(actor_role) = self
+            .authorize(group_id, actor, Permission::ManageRoles, sender_tag, ACTION)
+            .await
+        else {
+            return;
+        };
+        let current_role = match self.db.get_member_role(group_id, target).await {
+            Ok(Some(role)) => role,
+            Ok(None) => {
+                self.send_error(
+                    sender_tag,
+                    ACTION,
+                    ErrorCode::NotMember,
+                    "not a member of this group",
+                )
+                .await;
+                return;
+            }
+            Err(e) => {
+                log::error!("DB error resolving role in {}: {}", group_id, e);
+                self.send_error(sender_tag, ACTION, ErrorCode::Internal, "set role failed")
+                    .await;
+                return;
+            }
+        };
+        // Neither the target's current role nor the new one may reach the actor's own
+        if current_role >= actor_role || new_role >= actor_role {
+            self.send_error(sender_tag, ACTION, ErrorCode::Unauthorized, "unauthorized")
+                .await;
+            return;
+        }
+        match self.db.set_member_role(group_id, target, new_role).await {
+            Ok(true) => {
+                self.send_encapsulated_reply(sender_tag, "success".into(), ACTION)
+                    .await;
+                let notice = Response::RoleChanged(RoleChanged {
+                    group_id: group_id.to_string(),
+                    role: new_role,
+                })
+                .content();
+                for tag in self.sessions_for(target) {
+                    self.send_notice(tag, notice.clone(), "roleChanged").await;
+                }
+            }
+            other => {
+                if let Err(e) = other {
+                    log::error!("DB error during setRole: {}", e);
+                }
+                self.send_error(sender_tag, ACTION, ErrorCode::Internal, "set role failed")
+                    .await;
+            }
+        }
+    }
+
+    /// Handle a client 'listGroups': page through discoverable groups.
+    async fn handle_list_groups(
+        &mut self,
+        sender_tag: AnonymousSenderTag,
+        name_prefix: Option<&str>,
+        cursor: Option<&str>,
+        limit: Option<u32>,
+    ) {
+        const ACTION: &str = "listGroupsResponse";
+        let name_prefix = name_prefix.filter(|p| !p.is_empty());
+        let cursor = cursor.filter(|c| !c.is_empty());
+        let limit = limit
+            .unwrap_or(LIST_GROUPS_DEFAULT_LIMIT)
+            .clamp(1, LIST_GROUPS_MAX_LIMIT);
+        match self
+            .db
+            .list_discoverable_groups(name_prefix, cursor, limit)
+            .await
+        {
+            Ok(groups) => {
+                // A full page means there may be more; resume after its last id
+                let next_cursor = if groups.len() == limit as usize {
+                    groups.last().map(|g| g.group_id.clone())
+                } else {
+                    None
+                };
+                let content = Response::GroupPage(GroupPage {
+                    groups,
+                    next_cursor,
+                })
+                .content();
+                self.send_encapsulated_reply(sender_tag, content, ACTION)
+                    .await;
+            }
+            Err(e) => {
+                log::error!("DB error during listGroups: {}", e);
+                self.send_error(
+                    sender_tag,
+                    ACTION,
+                    ErrorCode::Internal,
+                    "list groups failed",
+                )
+                .await;
+            }
+        }
+    }
+
+    /// Forward everything published on a group's channel to `sender_tag`.
+    async fn subscribe_to_group(&mut self, sender_tag: AnonymousSenderTag, group_id: &str) {
+        self.fanout.subscribe(sender_tag, group_id).await;
+    }
+
+    /// Stop forwarding a group's messages to every session of `username`.
+    fn unsubscribe_user_from_group(&mut self, username: &str, group_id: &str) {
+        for tag in self.sessions_for(username) {
+            self.fanout.unsubscribe(tag, group_id);
+        }
+    }
+
+    /// Handle a client 'sendGroup': append to the group's history and publish to its
+    /// channel.
+    async fn handle_send_group(
+        &mut self,
+        sender_tag: AnonymousSenderTag,
+        username: &str,
+        group_id: &str,
+        ciphertext: &str,
+    ) {
+        if ciphertext.len() > MAX_MESSAGE_BYTES {
+            self.send_error(
+                sender_tag,
+                "sendGroupResponse",
+                ErrorCode::MessageTooLarge,
+                &format!("message exceeds {} bytes", MAX_MESSAGE_BYTES),
+            )
+            .await;
+            return;
+        }
+        if self
+            .authorize(
+                group_id,
+                username,
+                Permission::Send,
+                sender_tag,
+                "sendGroupResponse",
+            )
+            .await
+            .is_none()
+        {
+            return;
+        }
+        // store the encrypted message in the group's history for pull-based fan-out
+        let payload = serde_json::to_string(&GroupMessage {
+            group_id: group_id.to_string(),
+            sender: username.to_string(),
+            ciphertext: ciphertext.to_string(),
+        })
+        .unwrap_or_default();
+        if let Err(e) = self.store.append(group_id, &payload).await {
+            log::error!("failed to store message for group {}: {}", group_id, e);
+            self.send_error(
+                sender_tag,
+                "sendGroupResponse",
+                ErrorCode::Internal,
+                "message could not be stored",
+            )
+            .await;
+            return;
+        }
+        // publish to the group's channel for push delivery to connected members, signed
+        // like a notice; those that miss it still fetch it from the history
+        let notice = Reply {
+            action: "groupMessage".to_string(),
+            status: Status::Ok,
+            error_code: None,
+            content: payload,
+            context: None,
+            signature: None,
+        };
+        match self.seal_reply(notice) {
+            Ok(push) => {
+                if let Err(e) = self.broker.publish(group_id, &push).await {
+                    log::warn!("failed to publish message for group {}: {}", group_id, e);
+                }
+            }
+            Err(e) => log::error!("failed to sign message for group {}: {}", group_id, e),
+        }
+        self.send_encapsulated_reply(sender_tag, "success".into(), "sendGroupResponse")
+            .await;
+    }
+
+    /// Handle a client request to fetch new group messages from the group's history
+    async fn handle_fetch_group(
+        &mut self,
+        sender_tag: AnonymousSenderTag,
+        username: &str,
+        group_id: &str,
+        last_seen_id: &str,
+    ) {
+        if self
+            .authorize(
+                group_id,
+                username,
+                Permission::Read,
+                sender_tag,
+                "fetchGroupResponse",
+            )
+            .await
+            .is_none()
+        {
+            return;
+        }
+        let msgs = match self
+            .store
+            .read_after(group_id, last_seen_id, FETCH_BATCH_SIZE)
+            .await
+        {
+            Ok(msgs) => msgs,
+            Err(e) => {
+                log::error!("failed to read messages of group {}: {}", group_id, e);
+                self.send_error(
+                    sender_tag,
+                    "fetchGroupResponse",
+                    ErrorCode::Internal,
+                    "messages could not be read",
+                )
+                .await;
+                return;
+            }
+        };
+        // Send back all new messages
+        let content = Response::Messages(MessageBatch { messages: msgs }).content();
+        self.send_encapsulated_reply(sender_tag, content, "fetchGroupResponse")
+            .await;
+    }
+
+    /// Record a verified request in the replay cache by its canonical form; replies with an
+    /// error if the request is stale or was seen before.
+    async fn check_replay(
+        &mut self,
+        requester: &str,
+        timestamp: i64,
+        canonical_request: &str,
+        sender_tag: AnonymousSenderTag,
+        action: &str,
+    ) -> bool {
+        if self.replay.accept(requester, timestamp, canonical_request) {
+            return true;
+        }
+        self.send_error(
+            sender_tag,
+            action,
+            ErrorCode::StaleRequest,
+            "stale or replayed request",
+        )
+        .await;
+        false
+    }
+
+    /// Find the operator whose key signed the canonical form of `request`.
+    async fn verify_operator(&self, request: &Value) -> Option<String> {
+        let operators = match self.db.get_operators().await {
+            Ok(ops) => ops,
+            Err(e) => {
+                log::error!("DB error loading operators: {}", e);
+                return None;
+            }
+        };
+        operators.into_iter().find_map(|(name, public_key)| {
+            self.crypto
+                .verify_cached_request(&operator_cache_key(&name), &public_key, request)
+                .then_some(name)
+        })
+    }
+
+    /// All sender tags with an active session for `username`.
+    fn sessions_for(&self, username: &str) -> Vec<AnonymousSenderTag> {
+        self.sessions.tags_for(username)
+    }
+
+    /// Close `sender_tag`'s session, if it has one, and stop forwarding to it.
+    fn end_session(&mut self, sender_tag: AnonymousSenderTag, reason: &str) {
+        self.fanout.remove_session(sender_tag);
+        if let Some(session) = self.sessions.close(sender_tag) {
+            log::info!(
+                "session {} of {} ended: {}",
+                session.id,
+                session.username,
+                reason
+            );
+        }
+    }
+
+    /// End every session that has been idle for longer than the timeout.
+    fn expire_idle_sessions(&mut self) {
+        for (sender_tag, session) in self.sessions.expire_idle(Instant::now()) {
+            self.fanout.remove_session(sender_tag);
+            log::info!(
+                "session {} of {} expired after {:?} idle",
+                session.id,
+                session.username,
+                self.sessions.idle_timeout()
+            );
+        }
+    }
+
+    /// Permission check every group-scoped handler goes through: resolves the requester's
+    /// role in the group and replies with an error unless it grants `permission`.
+    async fn authorize(
+        &self,
+        group_id: &str,
+        username: &str,
+        permission: Permission,
+        sender_tag: AnonymousSenderTag,
+        action: &str,
+    ) -> Option<Role> {
+        let (code, message) = match self.db.get_member_role(group_id, username).await {
+            Ok(Some(role)) if role.allows(permission) => return Some(role),
+            Ok(Some(_)) => (ErrorCode::Unauthorized, "unauthorized"),
+            Ok(None) => (ErrorCode::NotMember, "not a member of this group"),
+            Err(e) => {
+                log::error!("DB error resolving role in {}: {}", group_id, e);
+                (ErrorCode::Internal, "role lookup failed")
+            }
+        };
+        self.send_error(sender_tag, action, code, message).await;
+        None
+    }
+
+    /// Check the group's ban list, treating database errors as "banned".
+    async fn is_banned(&self, group_id: &str, username: &str) -> bool {
+        match self.db.is_user_banned(group_id, username).await {
+            Ok(banned) => banned,
+            Err(e) => {
+                log::error!("DB error checking bans of {}: {}", group_id, e);
+                true
+            }
+        }
+    }
+
+    /// Check group membership, treating database errors as "not a member".
+    async fn is_member(&self, group_id: &str, username: &str) -> bool {
+        match self.db.is_group_member(group_id, username).await {
+            Ok(member) => member,
+            Err(e) => {
+                log::error!("DB error checking membership of {}: {}", group_id, e);
+                false
+            }
+        }
+    }
+
+    /// Sign and send a successful JSON reply to the request being handled over the
+    /// mixnet using SURBs, echoing its `requestId` as `context`.
+    async fn send_encapsulated_reply(
+        &self,
+        recipient: AnonymousSenderTag,
+        content: String,
+        action: &str,
+    ) {
+        let reply = Reply {
+            action: action.to_string(),
+            status: Status::Ok,
+            error_code: None,
+            content,
+            context: self.request_id.clone(),
+            signature: None,
+        };
+        self.send_signed_reply(recipient, reply).await;
+    }
+
+    /// Answer the request being handled with `pending`: it waits on someone else, like a
+    /// registration on an operator or a knock on a group admin.
+    async fn send_pending(&self, recipient: AnonymousSenderTag, action: &str) {
+        let reply = Reply {
+            action: action.to_string(),
+            status: Status::Pending,
+            error_code: None,
+            content: "pending".to_string(),
+            context: self.request_id.clone(),
+            signature: None,
+        };
+        self.send_signed_reply(recipient, reply).await;
+    }
+
+    /// Push a notice to a session. Notices are not replies, so they carry no `context`.
+    async fn send_notice(&self, recipient: AnonymousSenderTag, content: String, action: &str) {
+        let reply = Reply {
+            action: action.to_string(),
+            status: Status::Ok,
+            error_code: None,
+            content,
+            context: None,
+            signature: None,
+        };
+        self.send_signed_reply(recipient, reply).await;
+    }
+
+    /// Push the notice of a recent key rotation, while its grace period lasts, ahead of a
+    /// reply to a client that may have been offline during the rotation and still pins
+    /// the old key. It would otherwise drop the reply.
+    async fn announce_key_rotation(&self, recipient: AnonymousSenderTag) {
+        if let Some(rotation) = self.crypto.key_rotation() {
+            self.send_rotation_notice(recipient, &rotation).await;
+        }
+    }
+
+    /// Push a 'serverKeyRotated' notice. It is signed with the previous key, which
+    /// clients that have not seen the rotation yet still pin.
+    async fn send_rotation_notice(
+        &self,
+        recipient: AnonymousSenderTag,
+        rotation: &ServerKeyRotated,
+    ) {
+        let mut reply = Reply {
+            action: "serverKeyRotated".to_string(),
+            status: Status::Ok,
+            error_code: None,
+            content: Response::ServerKeyRotated(rotation.clone()).content(),
+            context: None,
+            signature: None,
+        };
+        let value = serde_json::to_value(&reply).unwrap_or_default();
+        match self.crypto.sign_with_previous_key(&canonical_json(&value)) {
+            Ok(signature) => {
+                reply.signature = Some(signature);
+                let msg = serde_json::to_string(&reply).unwrap_or_default();
+                let _ = self.sender.send_reply(recipient, msg.into_bytes()).await;
+            }
+            Err(e) => log::error!("serverKeyRotated - failed to sign notice: {}", e),
+        }
+    }
+
+    /// Answer `action` with an error: a stable `code` plus a human-readable `message`.
+    async fn send_error(
+        &self,
+        recipient: AnonymousSenderTag,
+        action: &str,
+        code: ErrorCode,
+        message: &str,
+    ) {
+        let reply = Reply {
+            action: action.to_string(),
+            status: Status::Error,
+            error_code: Some(code),
+            content: message.to_string(),
+            context: self.request_id.clone(),
+            signature: None,
+        };
+        self.send_signed_reply(recipient, reply).await;
+    }
+
+    /// Sign the canonical JSON of `reply` and send it.
+    async fn send_signed_reply(&self, recipient: AnonymousSenderTag, reply: Reply) {
+        match self.seal_reply(reply) {
+            Ok(msg) => {
+                let _ = self.sender.send_reply(recipient, msg.into_bytes()).await;
+            }
+            Err(_) => log::error!("sendEncapsulatedReply - failed to sign message"),
+        }
+    }
+
+    /// Sign `reply` with the server key and serialize it.
+    fn seal_reply(&self, mut reply: Reply) -> anyhow::Result<String> {
+        let value = serde_json::to_value(&reply)?;
+        reply.signature = Some(
+            self.crypto
+                .sign_message(&self.client_id, &canonical_json(&value))?,
+        );
+        Ok(serde_json::to_string(&reply)?)
+    }
+}
+
+/// Certificate cache entry name for an operator, kept apart from usernames.
+fn operator_cache_key(name: &str) -> String {
+    format!("operator:{}", name)
 }
@@ -0,0 +1,108 @@
+//! Per-group member roles and the permissions each role grants.
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+
+/// Role of a member within one group, ordered from least to most privileged.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Role {
+    /// May read the group but not post (announcement audiences).
+    ReadOnly,
+    /// Regular member: read and post.
+    Member,
+    /// Keeps order: invites, handles join requests and kicks members.
+    Moderator,
+    /// Manages the group: everything a moderator can do, plus bans and role changes.
+    Admin,
+    /// Creator of the group; cannot be removed or demoted.
+    Owner,
+}
+
+/// Operation on a group that requires a minimum role.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Permission {
+    Read,
+    Send,
+    Invite,
+    Kick,
+    Ban,
+    ManageRoles,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_role_permissions_and_parsing() {
+        assert!(Role::ReadOnly.allows(Permission::Read));
+        assert!(!Role::ReadOnly.allows(Permission::Send));
+        assert!(Role::Member.allows(Permission::Send));
+        assert!(!Role::Member.allows(Permission::Invite));
+        assert!(Role::Moderator.allows(Permission::Kick));
+        assert!(!Role::Moderator.allows(Permission::Ban));
+        assert!(Role::Admin.allows(Permission::ManageRoles));
+        assert!(Role::Owner > Role::Admin);
+        for role in [
+            Role::ReadOnly,
+            Role::Member,
+            Role::Moderator,
+            Role::Admin,
+            Role::Owner,
+        ] {
+            assert_eq!(role.as_str().parse::<Role>().unwrap(), role);
+        }
+        assert!("superuser".parse::<Role>().is_err());
+    }
+}
+
+impl Role {
+    /// Name of the role as stored in the database and used on the wire.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::ReadOnly => "readOnly",
+            Role::Member => "member",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+            Role::Owner => "owner",
+        }
+    }
+
+    /// Whether this role grants `permission`.
+    pub fn allows(self, permission: Permission) -> bool {
+        self >= permission.minimum_role()
+    }
+}
+
+impl Permission {
+    /// Least privileged role holding this permission.
+    fn minimum_role(self) -> Role {
+        match self {
+            Permission::Read => Role::ReadOnly,
+            Permission::Send => Role::Member,
+            Permission::Invite | Permission::Kick => Role::Moderator,
+            Permission::Ban | Permission::ManageRoles => Role::Admin,
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Role {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "readOnly" => Ok(Role::ReadOnly),
+            "member" => Ok(Role::Member),
+            "moderator" => Ok(Role::Moderator),
+            "admin" => Ok(Role::Admin),
+            "owner" => Ok(Role::Owner),
+            other => anyhow::bail!("unknown role: {}", other),
+        }
+    }
+}
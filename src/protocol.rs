@@ -0,0 +1,713 @@
+//! Typed model of the JSON protocol spoken over the mixnet, shared by the server and
+//! clients. See `docs/Protocol.md` for the wire format of each message.
+use crate::permissions::Role;
+use anyhow::Result;
+use serde::{Deserialize, Deserializer, Serialize, de::Error as _};
+use serde_json::Value;
+use std::fmt;
+
+/// Version of the protocol described here. Requests carrying a different `version` are
+/// rejected; bump it on incompatible changes.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Every request `action` the server understands.
+pub const ACTIONS: &[&str] = &[
+    "serverInfo",
+    "register",
+    "approveGroup",
+    "getChallenge",
+    "connect",
+    "disconnect",
+    "listPending",
+    "rejectRegistration",
+    "addOperator",
+    "removeOperator",
+    "rotateServerKey",
+    "createGroup",
+    "inviteGroup",
+    "acceptInvite",
+    "declineInvite",
+    "joinGroup",
+    "knock",
+    "listJoinRequests",
+    "leaveGroup",
+    "kickMember",
+    "banMember",
+    "setRole",
+    "listGroups",
+    "sendGroup",
+    "fetchGroup",
+];
+
+/// A client request, tagged by its `action` field.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(
+    tag = "action",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum Request {
+    ServerInfo,
+    Register {
+        #[serde(deserialize_with = "non_empty")]
+        username: String,
+        #[serde(deserialize_with = "non_empty")]
+        public_key: String,
+    },
+    ApproveGroup {
+        #[serde(deserialize_with = "non_empty")]
+        username: String,
+    },
+    GetChallenge,
+    Connect {
+        #[serde(deserialize_with = "non_empty")]
+        username: String,
+        #[serde(deserialize_with = "non_empty")]
+        nonce: String,
+    },
+    Disconnect,
+    ListPending {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cursor: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        limit: Option<u32>,
+    },
+    RejectRegistration {
+        #[serde(deserialize_with = "non_empty")]
+        username: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cooldown_secs: Option<i64>,
+    },
+    AddOperator {
+        #[serde(deserialize_with = "non_empty")]
+        name: String,
+        #[serde(deserialize_with = "non_empty")]
+        public_key: String,
+    },
+    RemoveOperator {
+        #[serde(deserialize_with = "non_empty")]
+        name: String,
+    },
+    RotateServerKey,
+    CreateGroup {
+        #[serde(deserialize_with = "non_empty")]
+        username: String,
+        #[serde(deserialize_with = "non_empty")]
+        group_name: String,
+        #[serde(default)]
+        is_public: bool,
+        #[serde(default)]
+        is_discoverable: bool,
+    },
+    InviteGroup {
+        #[serde(deserialize_with = "non_empty")]
+        group_id: String,
+        #[serde(deserialize_with = "non_empty")]
+        username: String,
+    },
+    AcceptInvite {
+        #[serde(deserialize_with = "non_empty")]
+        group_id: String,
+    },
+    DeclineInvite {
+        #[serde(deserialize_with = "non_empty")]
+        group_id: String,
+    },
+    JoinGroup {
+        #[serde(deserialize_with = "non_empty")]
+        group_id: String,
+    },
+    Knock {
+        #[serde(deserialize_with = "non_empty")]
+        group_id: String,
+    },
+    ListJoinRequests {
+        #[serde(deserialize_with = "non_empty")]
+        group_id: String,
+    },
+    LeaveGroup {
+        #[serde(deserialize_with = "non_empty")]
+        group_id: String,
+    },
+    KickMember {
+        #[serde(deserialize_with = "non_empty")]
+        group_id: String,
+        #[serde(deserialize_with = "non_empty")]
+        username: String,
+    },
+    BanMember {
+        #[serde(deserialize_with = "non_empty")]
+        group_id: String,
+        #[serde(deserialize_with = "non_empty")]
+        username: String,
+    },
+    SetRole {
+        #[serde(deserialize_with = "non_empty")]
+        group_id: String,
+        #[serde(deserialize_with = "non_empty")]
+        username: String,
+        role: Role,
+    },
+    ListGroups {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        name_prefix: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cursor: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        limit: Option<u32>,
+    },
+    SendGroup {
+        #[serde(deserialize_with = "non_empty")]
+        group_id: String,
+        ciphertext: String,
+    },
+    FetchGroup {
+        #[serde(deserialize_with = "non_empty")]
+        group_id: String,
+        #[serde(deserialize_with = "non_empty")]
+        last_seen_id: String,
+    },
+}
+
+/// A request together with the fields that authenticate it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SignedRequest {
+    #[serde(flatten)]
+    pub request: Request,
+    /// [`PROTOCOL_VERSION`] the client speaks; assumed current when absent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u32>,
+    /// Client-chosen id echoed as the `context` of the reply.
+    #[serde(rename = "requestId", default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// Unix milliseconds; required on every signed request except `connect`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<i64>,
+    /// Detached signature over [`canonical_json`] of the request.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+/// Envelope of every message the server sends: replies and pushed notices.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Reply {
+    pub action: String,
+    pub status: Status,
+    /// Set when `status` is `error`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<ErrorCode>,
+    /// Status text, the JSON encoding of a structured [`Response`], or for errors a
+    /// human-readable message.
+    pub content: String,
+    /// `requestId` of the request this answers; absent on notices.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context: Option<String>,
+    /// Server signature over [`canonical_json`] of the reply.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+/// Outcome of a request, carried by every [`Reply`]. Pushed notices are `ok`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Status {
+    Ok,
+    Pending,
+    Error,
+}
+
+/// Stable, machine-readable reason for an error [`Reply`]. Clients should branch on these
+/// rather than on the human-readable `content`, whose wording may change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ErrorCode {
+    /// Not valid JSON, no `action`, or missing or mistyped fields.
+    MalformedRequest,
+    UnknownAction,
+    /// The request's `version` is not the server's [`PROTOCOL_VERSION`].
+    UnsupportedVersion,
+    /// The signature is missing or does not verify against the requester's key.
+    BadSignature,
+    /// The timestamp is outside the accepted window or the request was already accepted.
+    StaleRequest,
+    /// The `connect` nonce was not issued to this sender, has expired or was used.
+    InvalidChallenge,
+    /// The sender tag has no connected session.
+    NotConnected,
+    /// The requester's role, or operator status, does not allow the action.
+    Unauthorized,
+    /// The user is not registered or not yet approved.
+    NotRegistered,
+    AlreadyRegistered,
+    /// A rejected registration may not be retried yet.
+    RegistrationCooldown,
+    NoSuchRegistration,
+    OperatorExists,
+    NoSuchOperator,
+    LastOperator,
+    /// The server key was rotated recently and its grace period has not ended.
+    RotationInProgress,
+    NoSuchGroup,
+    NotMember,
+    AlreadyMember,
+    AlreadyInvited,
+    NoSuchInvite,
+    /// The requester, or the user acted on, is banned from the group.
+    Banned,
+    AlreadyBanned,
+    /// A private group is joined by invite or knock.
+    GroupPrivate,
+    /// A public group is joined directly rather than by knocking.
+    GroupPublic,
+    JoinRequestPending,
+    OwnerCannotLeave,
+    /// The `ciphertext` exceeds [`Limits::max_message_bytes`].
+    MessageTooLarge,
+    /// The server failed to complete the request.
+    Internal,
+}
+
+/// An error [`Reply`], as surfaced to client code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+/// Typed `content` of a [`Reply`].
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum Response {
+    /// `success` or `pending`.
+    Status(String),
+    ServerInfo(ServerInfo),
+    Challenge(Challenge),
+    GroupCreated(GroupCreated),
+    PendingPage(PendingPage),
+    GroupPage(GroupPage),
+    JoinRequests(JoinRequests),
+    Messages(MessageBatch),
+    GroupInvite(GroupInvite),
+    GroupJoined(GroupJoined),
+    JoinRequest(JoinRequest),
+    GroupRemoved(GroupRemoved),
+    RoleChanged(RoleChanged),
+    ServerKeyRotated(ServerKeyRotated),
+}
+
+/// What the server supports, answered to 'serverInfo' before any authentication.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerInfo {
+    pub version: u32,
+    pub actions: Vec<String>,
+    pub limits: Limits,
+    /// ASCII-armored key the server signs its replies with.
+    pub public_key: String,
+}
+
+/// Limits the server enforces on requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Limits {
+    /// Largest `ciphertext` accepted by 'sendGroup', in bytes.
+    pub max_message_bytes: usize,
+    /// Most messages returned by one 'fetchGroup'.
+    pub fetch_batch_size: usize,
+    /// Largest `limit` accepted by 'listGroups' and 'listPending'.
+    pub max_page_size: u32,
+    /// Seconds a session may go without a signed request before it expires and live
+    /// delivery to it stops. Zero from servers that do not report it.
+    #[serde(default)]
+    pub session_idle_secs: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Challenge {
+    pub nonce: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupCreated {
+    pub group_id: String,
+}
+
+/// A registration awaiting operator approval.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PendingUser {
+    pub username: String,
+    pub fingerprint: Option<String>,
+    pub requested_at: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PendingPage {
+    pub pending: Vec<PendingUser>,
+    pub next_cursor: Option<String>,
+}
+
+/// Public listing entry for a discoverable group.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupSummary {
+    pub group_id: String,
+    pub group_name: String,
+    pub member_count: i64,
+    pub is_public: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupPage {
+    pub groups: Vec<GroupSummary>,
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JoinRequests {
+    pub group_id: String,
+    pub requests: Vec<String>,
+}
+
+/// History entries returned by 'fetchGroup' as `(payload, entry id)` pairs. Entry ids
+/// come from the server's message store and are opaque to clients.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MessageBatch {
+    pub messages: Vec<(String, String)>,
+}
+
+/// A group message as kept in the group's history and pushed to connected members.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupMessage {
+    pub group_id: String,
+    pub sender: String,
+    pub ciphertext: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupInvite {
+    pub group_id: String,
+    pub invited_by: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupJoined {
+    pub group_id: String,
+    pub approved_by: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JoinRequest {
+    pub group_id: String,
+    pub username: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupRemoved {
+    pub group_id: String,
+    /// `kicked` or `banned`.
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoleChanged {
+    pub group_id: String,
+    pub role: Role,
+}
+
+/// Notice that the server signs with a new key from now on. The notice is signed with
+/// the previous key, which also vouches for the new key in `handover`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerKeyRotated {
+    /// ASCII-armored key the server signs its replies with after the rotation.
+    pub new_key: String,
+    /// Detached signature over `new_key` by the previous key.
+    pub handover: String,
+    /// Unix seconds until which the previous key still signs this notice.
+    pub grace_until: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_request_and_response_round_trip() {
+        let raw = json!({
+            "action": "createGroup",
+            "username": "alice",
+            "groupName": "rust",
+            "isPublic": true,
+            "requestId": "r1",
+            "timestamp": 1700000000000i64,
+            "signature": "sig",
+        });
+        let signed: SignedRequest = serde_json::from_value(raw.clone()).unwrap();
+        assert_eq!(
+            signed.request,
+            Request::CreateGroup {
+                username: "alice".into(),
+                group_name: "rust".into(),
+                is_public: true,
+                is_discoverable: false,
+            }
+        );
+        assert_eq!(signed.request.action(), "createGroup");
+        assert_eq!(signed.timestamp, Some(1700000000000));
+        assert_eq!(signed.request_id.as_deref(), Some("r1"));
+        assert!(
+            serde_json::from_value::<SignedRequest>(json!({"action": "joinGroup", "groupId": ""}))
+                .is_err()
+        );
+        assert!(serde_json::from_value::<SignedRequest>(json!({"action": "nope"})).is_err());
+        assert!(
+            serde_json::from_value::<Request>(
+                json!({"action": "setRole", "groupId": "g", "username": "bob", "role": "boss"})
+            )
+            .is_err()
+        );
+
+        assert_eq!(
+            canonical_json(&raw),
+            r#"{"action":"createGroup","groupName":"rust","isPublic":true,"requestId":"r1","timestamp":1700000000000,"username":"alice"}"#
+        );
+        let reordered = json!({"timestamp": 1700000000000i64, "isPublic": true, "requestId": "r1",
+            "username": "alice", "groupName": "rust", "action": "createGroup"});
+        assert_eq!(canonical_json(&raw), canonical_json(&reordered));
+
+        let created = Response::GroupCreated(GroupCreated {
+            group_id: "g1".into(),
+        });
+        assert_eq!(created.content(), r#"{"groupId":"g1"}"#);
+        assert_eq!(
+            Response::parse("createGroupResponse", &created.content()).unwrap(),
+            created
+        );
+        assert!(!Request::ServerInfo.is_signed());
+        assert!(
+            Request::LeaveGroup {
+                group_id: "g".into()
+            }
+            .is_signed()
+        );
+        let info = Response::ServerInfo(ServerInfo {
+            version: PROTOCOL_VERSION,
+            actions: vec!["serverInfo".into()],
+            limits: Limits {
+                max_message_bytes: 1,
+                fetch_batch_size: 2,
+                max_page_size: 3,
+                session_idle_secs: 4,
+            },
+            public_key: "key".into(),
+        });
+        assert_eq!(
+            Response::parse("serverInfoResponse", &info.content()).unwrap(),
+            info
+        );
+        let rotated = Response::ServerKeyRotated(ServerKeyRotated {
+            new_key: "new".into(),
+            handover: "sig".into(),
+            grace_until: 1,
+        });
+        assert_eq!(
+            rotated.content(),
+            r#"{"newKey":"new","handover":"sig","graceUntil":1}"#
+        );
+        assert_eq!(
+            Response::parse("serverKeyRotated", &rotated.content()).unwrap(),
+            rotated
+        );
+        assert!(Request::RotateServerKey.is_operator_action());
+        let disconnect: Request = serde_json::from_value(json!({"action": "disconnect"})).unwrap();
+        assert_eq!(disconnect.action(), "disconnect");
+        assert!(disconnect.is_signed() && !disconnect.is_operator_action());
+
+        let reply: Reply = serde_json::from_value(json!({
+            "action": "joinGroupResponse",
+            "status": "error",
+            "errorCode": "groupPrivate",
+            "content": "group is private",
+        }))
+        .unwrap();
+        assert_eq!(
+            reply.error(),
+            Some(ServerError {
+                code: ErrorCode::GroupPrivate,
+                message: "group is private".into(),
+            })
+        );
+    }
+}
+
+impl Request {
+    /// The `action` this request is sent as.
+    pub fn action(&self) -> &'static str {
+        match self {
+            Request::ServerInfo => "serverInfo",
+            Request::Register { .. } => "register",
+            Request::ApproveGroup { .. } => "approveGroup",
+            Request::GetChallenge => "getChallenge",
+            Request::Connect { .. } => "connect",
+            Request::Disconnect => "disconnect",
+            Request::ListPending { .. } => "listPending",
+            Request::RejectRegistration { .. } => "rejectRegistration",
+            Request::AddOperator { .. } => "addOperator",
+            Request::RemoveOperator { .. } => "removeOperator",
+            Request::RotateServerKey => "rotateServerKey",
+            Request::CreateGroup { .. } => "createGroup",
+            Request::InviteGroup { .. } => "inviteGroup",
+            Request::AcceptInvite { .. } => "acceptInvite",
+            Request::DeclineInvite { .. } => "declineInvite",
+            Request::JoinGroup { .. } => "joinGroup",
+            Request::Knock { .. } => "knock",
+            Request::ListJoinRequests { .. } => "listJoinRequests",
+            Request::LeaveGroup { .. } => "leaveGroup",
+            Request::KickMember { .. } => "kickMember",
+            Request::BanMember { .. } => "banMember",
+            Request::SetRole { .. } => "setRole",
+            Request::ListGroups { .. } => "listGroups",
+            Request::SendGroup { .. } => "sendGroup",
+            Request::FetchGroup { .. } => "fetchGroup",
+        }
+    }
+
+    /// Whether the request must be signed. Only the requests a client makes before it
+    /// has a registered key are not.
+    pub fn is_signed(&self) -> bool {
+        !matches!(self, Request::ServerInfo | Request::GetChallenge)
+    }
+
+    /// Whether the request must be signed by a server operator key.
+    pub fn is_operator_action(&self) -> bool {
+        matches!(
+            self,
+            Request::ApproveGroup { .. }
+                | Request::ListPending { .. }
+                | Request::RejectRegistration { .. }
+                | Request::AddOperator { .. }
+                | Request::RemoveOperator { .. }
+                | Request::RotateServerKey
+        )
+    }
+}
+
+impl Response {
+    /// Encode as the `content` string of a [`Reply`].
+    pub fn content(&self) -> String {
+        match self {
+            Response::Status(status) => status.clone(),
+            other => serde_json::to_string(other).unwrap_or_default(),
+        }
+    }
+
+    /// Decode the `content` of a reply or notice sent with `action`.
+    pub fn parse(action: &str, content: &str) -> Result<Response> {
+        if content == "success" || content == "pending" {
+            return Ok(Response::Status(content.to_string()));
+        }
+        Ok(match action {
+            "serverInfoResponse" => Response::ServerInfo(serde_json::from_str(content)?),
+            "getChallengeResponse" => Response::Challenge(serde_json::from_str(content)?),
+            "createGroupResponse" => Response::GroupCreated(serde_json::from_str(content)?),
+            "listPendingResponse" => Response::PendingPage(serde_json::from_str(content)?),
+            "listGroupsResponse" => Response::GroupPage(serde_json::from_str(content)?),
+            "listJoinRequestsResponse" => Response::JoinRequests(serde_json::from_str(content)?),
+            "fetchGroupResponse" => Response::Messages(serde_json::from_str(content)?),
+            "groupInvite" => Response::GroupInvite(serde_json::from_str(content)?),
+            "groupJoined" => Response::GroupJoined(serde_json::from_str(content)?),
+            "joinRequest" => Response::JoinRequest(serde_json::from_str(content)?),
+            "groupRemoved" => Response::GroupRemoved(serde_json::from_str(content)?),
+            "roleChanged" => Response::RoleChanged(serde_json::from_str(content)?),
+            "serverKeyRotated" => Response::ServerKeyRotated(serde_json::from_str(content)?),
+            other => anyhow::bail!("unexpected content for {}: {}", other, content),
+        })
+    }
+}
+
+impl Reply {
+    /// The error this reply carries, if its status is `error`.
+    pub fn error(&self) -> Option<ServerError> {
+        (self.status == Status::Error).then(|| ServerError {
+            code: self.error_code.unwrap_or(ErrorCode::Internal),
+            message: self.content.clone(),
+        })
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({:?})", self.message, self.code)
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+/// Canonical encoding of a request or reply that signatures are made over: object keys
+/// sorted, no insignificant whitespace, and the top-level `signature` field left out.
+pub fn canonical_json(request: &Value) -> String {
+    let mut out = String::new();
+    match request {
+        Value::Object(map) => write_object(
+            map.iter().filter(|(k, _)| k.as_str() != "signature"),
+            &mut out,
+        ),
+        other => write_canonical(other, &mut out),
+    }
+    out
+}
+
+fn write_canonical(value: &Value, out: &mut String) {
+    match value {
+        Value::Object(map) => write_object(map.iter(), out),
+        Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_canonical(item, out);
+            }
+            out.push(']');
+        }
+        // Scalars already have a single compact serialization
+        scalar => out.push_str(&scalar.to_string()),
+    }
+}
+
+fn write_object<'a>(entries: impl Iterator<Item = (&'a String, &'a Value)>, out: &mut String) {
+    let mut entries: Vec<_> = entries.collect();
+    entries.sort_by(|a, b| a.0.cmp(b.0));
+    out.push('{');
+    for (i, (key, value)) in entries.into_iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        out.push_str(&Value::String(key.clone()).to_string());
+        out.push(':');
+        write_canonical(value, out);
+    }
+    out.push('}');
+}
+
+/// Reject empty strings in required fields.
+fn non_empty<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    if value.is_empty() {
+        return Err(D::Error::custom("expected a non-empty string"));
+    }
+    Ok(value)
+}
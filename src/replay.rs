@@ -0,0 +1,217 @@
+//! Replay protection for signed requests: one-shot server challenges and a bounded cache
+//! of recently accepted timestamped requests.
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+use uuid::Uuid;
+
+/// How long an issued challenge can be answered.
+const CHALLENGE_TTL: Duration = Duration::from_secs(60);
+/// How old a request `timestamp` may be, in milliseconds.
+const TIMESTAMP_WINDOW_MS: i64 = 5 * 60 * 1000;
+/// How far ahead of the server clock a request `timestamp` may be, in milliseconds.
+/// Kept well below the window so that future-dated requests cannot crowd out current
+/// ones.
+const MAX_FUTURE_SKEW_MS: i64 = 30 * 1000;
+/// Upper bound on outstanding challenges and on remembered requests.
+const MAX_ENTRIES: usize = 10_000;
+
+/// Tracks issued challenges and accepted requests so that a captured request cannot be
+/// submitted a second time.
+pub struct ReplayGuard {
+    /// Outstanding challenges, at most one per sender tag: tag -> (nonce, issue time)
+    challenges: HashMap<String, (String, Instant)>,
+    /// (sender tag, nonce) in issue order; entries of used or replaced challenges are
+    /// skipped when met
+    challenge_order: VecDeque<(String, String)>,
+    /// Requests accepted within the timestamp window, oldest first: (timestamp, SHA-256
+    /// of the requester and the request's canonical form) -> requester. The signature is
+    /// not part of the digest, so re-armoring one does not make a new request.
+    seen: BTreeMap<(i64, [u8; 32]), String>,
+    /// Per requester, requests at or below this timestamp are rejected because their
+    /// digests may have been evicted from `seen`. Evicting by timestamp keeps a floor at
+    /// or below the requester's own accepted requests, so one user's requests never
+    /// lock out another's.
+    floors_ms: HashMap<String, i64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_challenges_and_timestamps() {
+        let mut guard = ReplayGuard::new();
+        let nonce = guard.issue_challenge("tag-a");
+        assert!(!guard.take_challenge(&nonce, "tag-b"));
+        assert!(guard.take_challenge(&nonce, "tag-a"));
+        assert!(!guard.take_challenge(&nonce, "tag-a"));
+
+        // A new challenge replaces the tag's previous one
+        let first = guard.issue_challenge("tag-a");
+        let second = guard.issue_challenge("tag-a");
+        assert!(!guard.take_challenge(&first, "tag-a"));
+        assert!(guard.take_challenge(&second, "tag-a"));
+
+        // A flood of getChallenge from one tag leaves other tags' challenges alone
+        let nonce = guard.issue_challenge("tag-a");
+        for _ in 0..2 * MAX_ENTRIES + 10 {
+            guard.issue_challenge("tag-b");
+        }
+        assert_eq!(guard.challenges.len(), 2);
+        assert!(guard.challenge_order.len() <= 2 * MAX_ENTRIES);
+        assert!(guard.take_challenge(&nonce, "tag-a"));
+
+        let now = Utc::now().timestamp_millis();
+        assert!(guard.accept("alice", now, "request-1"));
+        assert!(!guard.accept("alice", now, "request-1"));
+        assert!(guard.accept("bob", now, "request-1"));
+        assert!(guard.accept("alice", now, "request-2"));
+        assert!(!guard.accept("alice", now - TIMESTAMP_WINDOW_MS - 1, "request-3"));
+        assert!(!guard.accept("alice", now + MAX_FUTURE_SKEW_MS + 1_000, "request-4"));
+    }
+
+    #[test]
+    fn test_flood_does_not_lock_out_other_users() {
+        let mut guard = ReplayGuard::new();
+        let now = Utc::now().timestamp_millis();
+        // Far future-dated requests are refused outright
+        assert!(!guard.accept("mallory", now + 4 * 60 * 1000, "flood"));
+
+        // Fill the cache with requests dated as far ahead as allowed, then some more
+        let ahead = now + MAX_FUTURE_SKEW_MS - 5_000 - MAX_ENTRIES as i64;
+        for i in 0..MAX_ENTRIES + 10 {
+            let flood = format!("flood-{}", i);
+            assert!(guard.accept("mallory", ahead + i as i64, &flood));
+        }
+        assert_eq!(guard.seen.len(), MAX_ENTRIES);
+
+        // Current requests of other users still go through, and are still deduplicated
+        let now = Utc::now().timestamp_millis();
+        assert!(guard.accept("alice", now, "request-1"));
+        assert!(!guard.accept("alice", now, "request-1"));
+        assert!(guard.accept("alice", now + 1, "request-2"));
+        assert!(guard.accept("bob", now, "request-1"));
+        // Mallory's evicted requests cannot be replayed
+        assert!(!guard.accept("mallory", ahead, "flood-0"));
+    }
+}
+
+impl Default for ReplayGuard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReplayGuard {
+    pub fn new() -> Self {
+        ReplayGuard {
+            challenges: HashMap::new(),
+            challenge_order: VecDeque::new(),
+            seen: BTreeMap::new(),
+            floors_ms: HashMap::new(),
+        }
+    }
+
+    /// Issue a fresh single-use nonce bound to `sender_tag`, replacing any challenge the
+    /// tag still had, so that no sender can crowd out the challenges of others.
+    pub fn issue_challenge(&mut self, sender_tag: &str) -> String {
+        self.prune_challenges();
+        // Backstop against floods from many sender tags: drop the oldest challenge
+        if !self.challenges.contains_key(sender_tag) && self.challenges.len() >= MAX_ENTRIES {
+            while let Some((tag, nonce)) = self.challenge_order.pop_front() {
+                if self.is_current_challenge(&tag, &nonce) {
+                    self.challenges.remove(&tag);
+                    break;
+                }
+            }
+        }
+        // Replaced challenges leave entries behind in the order; compact it now and then
+        if self.challenge_order.len() >= 2 * MAX_ENTRIES {
+            let challenges = &self.challenges;
+            self.challenge_order
+                .retain(|(tag, nonce)| challenges.get(tag).is_some_and(|(n, _)| n == nonce));
+        }
+        let nonce = Uuid::new_v4().simple().to_string();
+        self.challenges
+            .insert(sender_tag.to_string(), (nonce.clone(), Instant::now()));
+        self.challenge_order
+            .push_back((sender_tag.to_string(), nonce.clone()));
+        nonce
+    }
+
+    /// Consume a challenge. Returns true if it is the one last issued to `sender_tag` and
+    /// has not expired or been used before.
+    pub fn take_challenge(&mut self, nonce: &str, sender_tag: &str) -> bool {
+        self.prune_challenges();
+        match self.challenges.get(sender_tag) {
+            Some((issued_nonce, issued))
+                if issued_nonce == nonce && issued.elapsed() < CHALLENGE_TTL =>
+            {
+                self.challenges.remove(sender_tag);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Accept a verified request of `requester` made at `timestamp_ms` (Unix
+    /// milliseconds), given in its canonical signed form, unless the timestamp is outside
+    /// the window or the request was already accepted.
+    pub fn accept(&mut self, requester: &str, timestamp_ms: i64, canonical_request: &str) -> bool {
+        let now = Utc::now().timestamp_millis();
+        let cutoff = now - TIMESTAMP_WINDOW_MS;
+        if timestamp_ms < cutoff || timestamp_ms > now + MAX_FUTURE_SKEW_MS {
+            return false;
+        }
+        let mut hasher = Sha256::new();
+        hasher.update(requester.as_bytes());
+        hasher.update([0]);
+        hasher.update(canonical_request.as_bytes());
+        let key = (timestamp_ms, <[u8; 32]>::from(hasher.finalize()));
+        let floor_ms = self.floors_ms.get(requester).copied().unwrap_or(i64::MIN);
+        if timestamp_ms <= floor_ms || self.seen.contains_key(&key) {
+            return false;
+        }
+        // Forget requests whose timestamps can no longer pass the window check
+        self.seen = self.seen.split_off(&(cutoff, [0; 32]));
+        self.floors_ms.retain(|_, floor_ms| *floor_ms >= cutoff);
+        if self.seen.len() >= MAX_ENTRIES
+            && let Some(((ts, _), evicted)) = self.seen.pop_first()
+        {
+            let floor_ms = self.floors_ms.entry(evicted).or_insert(ts);
+            *floor_ms = (*floor_ms).max(ts);
+        }
+        self.seen.insert(key, requester.to_string());
+        true
+    }
+
+    /// Whether `nonce` is the outstanding challenge of `sender_tag`.
+    fn is_current_challenge(&self, sender_tag: &str, nonce: &str) -> bool {
+        self.challenges
+            .get(sender_tag)
+            .is_some_and(|(issued_nonce, _)| issued_nonce == nonce)
+    }
+
+    fn prune_challenges(&mut self) {
+        while let Some((tag, nonce)) = self.challenge_order.front() {
+            let current = self.is_current_challenge(tag, nonce);
+            if current
+                && self
+                    .challenges
+                    .get(tag)
+                    .is_some_and(|(_, issued)| issued.elapsed() < CHALLENGE_TTL)
+            {
+                break;
+            }
+            if let Some((tag, _)) = self.challenge_order.pop_front()
+                && current
+            {
+                self.challenges.remove(&tag);
+            }
+        }
+    }
+}
@@ -0,0 +1,157 @@
+//! Connected sessions: the user each sender tag connected as, and when it was last heard
+//! from. Sessions that stay idle past the timeout expire.
+use nym_sdk::mixnet::AnonymousSenderTag;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+use uuid::Uuid;
+
+/// Idle time after which a session expires when no timeout is configured.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+/// A connected session.
+#[derive(Debug, Clone)]
+pub struct Session {
+    /// Random id naming the session in logs, unique across reconnects of the same tag
+    pub id: String,
+    pub username: String,
+    last_active: Instant,
+}
+
+/// Sessions by sender tag. A tag has at most one session; connecting again replaces it.
+pub struct SessionManager {
+    sessions: HashMap<AnonymousSenderTag, Session>,
+    idle_timeout: Duration,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sessions_replace_and_expire() {
+        let alice = AnonymousSenderTag::from_bytes([1; 16]);
+        let bob = AnonymousSenderTag::from_bytes([2; 16]);
+        let mut sessions = SessionManager::new(Duration::from_secs(60));
+        let first = sessions.open(alice, "alice");
+        let second = sessions.open(alice, "alice");
+        assert_ne!(first, second);
+        assert_eq!(sessions.len(), 1);
+        sessions.open(bob, "bob");
+        assert_eq!(sessions.username(alice), Some("alice"));
+        assert_eq!(sessions.tags_for("bob"), vec![bob]);
+
+        // Only sessions idle past the timeout expire
+        let later = Instant::now() + Duration::from_secs(61);
+        sessions.sessions.get_mut(&bob).unwrap().last_active = later;
+        let expired = sessions.expire_idle(later);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].0, alice);
+        assert_eq!(expired[0].1.id, second);
+        assert_eq!(sessions.username(alice), None);
+
+        assert_eq!(sessions.close(bob).map(|s| s.username), Some("bob".into()));
+        assert!(sessions.is_empty());
+    }
+}
+
+impl SessionManager {
+    /// Create a manager expiring sessions idle for longer than `idle_timeout`.
+    pub fn new(idle_timeout: Duration) -> Self {
+        SessionManager {
+            sessions: HashMap::new(),
+            idle_timeout,
+        }
+    }
+
+    /// How long a session may go without a request before it expires.
+    pub fn idle_timeout(&self) -> Duration {
+        self.idle_timeout
+    }
+
+    /// Change the idle timeout, also for the sessions already open.
+    pub fn set_idle_timeout(&mut self, idle_timeout: Duration) {
+        self.idle_timeout = idle_timeout;
+    }
+
+    /// Open a session for `username` on `sender_tag`, replacing any session the tag had.
+    /// Returns the new session's id.
+    pub fn open(&mut self, sender_tag: AnonymousSenderTag, username: &str) -> String {
+        let id = Uuid::new_v4().to_string();
+        self.sessions.insert(
+            sender_tag,
+            Session {
+                id: id.clone(),
+                username: username.to_string(),
+                last_active: Instant::now(),
+            },
+        );
+        id
+    }
+
+    /// The session of `sender_tag`, unless there is none or it has been idle too long.
+    pub fn get(&self, sender_tag: AnonymousSenderTag) -> Option<&Session> {
+        self.sessions
+            .get(&sender_tag)
+            .filter(|session| session.last_active.elapsed() <= self.idle_timeout)
+    }
+
+    /// The user `sender_tag` is connected as.
+    pub fn username(&self, sender_tag: AnonymousSenderTag) -> Option<&str> {
+        self.get(sender_tag)
+            .map(|session| session.username.as_str())
+    }
+
+    /// Record activity on `sender_tag`'s session, if it has one.
+    pub fn touch(&mut self, sender_tag: AnonymousSenderTag) {
+        if let Some(session) = self.sessions.get_mut(&sender_tag) {
+            session.last_active = Instant::now();
+        }
+    }
+
+    /// End `sender_tag`'s session, returning it.
+    pub fn close(&mut self, sender_tag: AnonymousSenderTag) -> Option<Session> {
+        self.sessions.remove(&sender_tag)
+    }
+
+    /// Remove and return the sessions idle for longer than the timeout at `now`.
+    pub fn expire_idle(&mut self, now: Instant) -> Vec<(AnonymousSenderTag, Session)> {
+        let expired: Vec<AnonymousSenderTag> = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| {
+                now.saturating_duration_since(session.last_active) > self.idle_timeout
+            })
+            .map(|(tag, _)| *tag)
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|tag| self.sessions.remove(&tag).map(|session| (tag, session)))
+            .collect()
+    }
+
+    /// Sender tags of every session.
+    pub fn tags(&self) -> Vec<AnonymousSenderTag> {
+        self.sessions.keys().copied().collect()
+    }
+
+    /// Sender tags of the sessions connected as `username`.
+    pub fn tags_for(&self, username: &str) -> Vec<AnonymousSenderTag> {
+        self.sessions
+            .iter()
+            .filter(|(_, session)| session.username == username)
+            .map(|(tag, _)| *tag)
+            .collect()
+    }
+
+    /// Number of sessions, including idle ones not yet expired.
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// Whether there are no sessions.
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+}
@@ -0,0 +1,199 @@
+//! Transports that carry requests to the server and replies back to their senders: the
+//! Nym mixnet, where replies travel over SURBs, and an in-memory loopback for tests.
+use anyhow::{Result, anyhow};
+use nym_sdk::mixnet::{
+    AnonymousSenderTag, MixnetClient, MixnetClientSender, MixnetMessageSender, ReconstructedMessage,
+};
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::mpsc;
+use tokio_stream::StreamExt;
+use uuid::Uuid;
+
+/// Boxed future returned by the transport traits, which are used as trait objects.
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Sends replies to the sender tag a request arrived with. Shared with the tasks that
+/// forward group messages, so it must be usable from any thread.
+pub trait ReplySender: Send + Sync {
+    fn send_reply(
+        &self,
+        recipient: AnonymousSenderTag,
+        message: Vec<u8>,
+    ) -> BoxFuture<'_, Result<()>>;
+}
+
+/// Source of inbound requests, each tagged with the sender to reply to.
+pub trait Transport: Send {
+    /// The next request, or None once the transport is closed.
+    fn next_request(&mut self) -> BoxFuture<'_, Option<ReconstructedMessage>>;
+
+    /// Handle for replying to the requests received from this transport.
+    fn reply_sender(&self) -> Arc<dyn ReplySender>;
+}
+
+/// In-memory transport: requests come from [`LoopbackClient`]s and each reply goes back
+/// to the client whose tag it is addressed to.
+pub struct Loopback {
+    requests: mpsc::UnboundedReceiver<ReconstructedMessage>,
+    request_tx: mpsc::UnboundedSender<ReconstructedMessage>,
+    replies: Arc<LoopbackReplies>,
+}
+
+/// Reply queues of the loopback clients, by sender tag.
+#[derive(Default)]
+struct LoopbackReplies {
+    clients: Mutex<HashMap<AnonymousSenderTag, mpsc::UnboundedSender<Vec<u8>>>>,
+}
+
+/// One client of a [`Loopback`] transport, with its own sender tag.
+pub struct LoopbackClient {
+    tag: AnonymousSenderTag,
+    requests: mpsc::UnboundedSender<ReconstructedMessage>,
+    replies: mpsc::UnboundedReceiver<Vec<u8>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_loopback_routes_replies_by_tag() -> Result<()> {
+        let mut transport = Loopback::new();
+        let mut alice = transport.client();
+        let mut bob = transport.client();
+        assert_ne!(alice.tag(), bob.tag());
+
+        bob.send(b"ping".to_vec())?;
+        let request = transport.next_request().await.unwrap();
+        assert_eq!(request.message, b"ping");
+        assert_eq!(request.sender_tag, Some(bob.tag()));
+
+        let replies = transport.reply_sender();
+        replies.send_reply(bob.tag(), b"pong".to_vec()).await?;
+        assert_eq!(bob.recv().await.as_deref(), Some(&b"pong"[..]));
+        assert!(alice.try_recv().is_none());
+
+        let gone = alice.tag();
+        drop(alice);
+        assert!(replies.send_reply(gone, b"late".to_vec()).await.is_err());
+        Ok(())
+    }
+}
+
+impl ReplySender for MixnetClientSender {
+    fn send_reply(
+        &self,
+        recipient: AnonymousSenderTag,
+        message: Vec<u8>,
+    ) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            MixnetMessageSender::send_reply(self, recipient, message).await?;
+            Ok(())
+        })
+    }
+}
+
+impl Transport for MixnetClient {
+    fn next_request(&mut self) -> BoxFuture<'_, Option<ReconstructedMessage>> {
+        Box::pin(self.next())
+    }
+
+    fn reply_sender(&self) -> Arc<dyn ReplySender> {
+        Arc::new(self.split_sender())
+    }
+}
+
+impl Default for Loopback {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Loopback {
+    pub fn new() -> Self {
+        let (request_tx, requests) = mpsc::unbounded_channel();
+        Loopback {
+            requests,
+            request_tx,
+            replies: Arc::default(),
+        }
+    }
+
+    /// Connect a new client under a fresh sender tag.
+    pub fn client(&self) -> LoopbackClient {
+        let tag = AnonymousSenderTag::from_bytes(*Uuid::new_v4().as_bytes());
+        let (reply_tx, replies) = mpsc::unbounded_channel();
+        if let Ok(mut clients) = self.replies.clients.lock() {
+            clients.insert(tag, reply_tx);
+        }
+        LoopbackClient {
+            tag,
+            requests: self.request_tx.clone(),
+            replies,
+        }
+    }
+}
+
+impl Transport for Loopback {
+    fn next_request(&mut self) -> BoxFuture<'_, Option<ReconstructedMessage>> {
+        Box::pin(self.requests.recv())
+    }
+
+    fn reply_sender(&self) -> Arc<dyn ReplySender> {
+        self.replies.clone()
+    }
+}
+
+impl ReplySender for LoopbackReplies {
+    fn send_reply(
+        &self,
+        recipient: AnonymousSenderTag,
+        message: Vec<u8>,
+    ) -> BoxFuture<'_, Result<()>> {
+        let sent = match self.clients.lock() {
+            Ok(mut clients) => match clients.get(&recipient).map(|tx| tx.send(message)) {
+                Some(Ok(())) => Ok(()),
+                // The client went away; forget its tag
+                Some(Err(_)) => {
+                    clients.remove(&recipient);
+                    Err(anyhow!("loopback client {} disconnected", recipient))
+                }
+                None => Err(anyhow!("unknown sender tag {}", recipient)),
+            },
+            Err(_) => Err(anyhow!("loopback state poisoned")),
+        };
+        Box::pin(async move { sent })
+    }
+}
+
+impl LoopbackClient {
+    /// Sender tag the server sees on this client's requests.
+    pub fn tag(&self) -> AnonymousSenderTag {
+        self.tag
+    }
+
+    /// Send a raw request to the server.
+    pub fn send(&self, message: Vec<u8>) -> Result<()> {
+        self.requests
+            .send(ReconstructedMessage {
+                message,
+                sender_tag: Some(self.tag),
+            })
+            .map_err(|_| anyhow!("loopback transport closed"))
+    }
+
+    /// Wait for the next frame the server sent to this client.
+    pub async fn recv(&mut self) -> Option<Vec<u8>> {
+        self.replies.recv().await
+    }
+
+    /// The next frame if one is already waiting.
+    pub fn try_recv(&mut self) -> Option<Vec<u8>> {
+        self.replies.try_recv().ok()
+    }
+}
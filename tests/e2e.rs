@@ -0,0 +1,443 @@
+//! End-to-end tests of the server over the in-memory loopback transport, with group
+//! history in the SQLite message store and the in-process broker: neither the mixnet
+//! nor Redis is needed.
+use anyhow::{Context, Result, bail};
+use chrono::Utc;
+use nymstr_groupd::{
+    broker::LocalBroker,
+    crypto_utils::{CryptoUtils, generate_cert, sign_request, verify_detached},
+    db_utils::DbUtils,
+    message_utils::{GroupCreationPolicy, MessageUtils},
+    protocol::{
+        ErrorCode, GroupMessage, PROTOCOL_VERSION, Reply, Request, Response, ServerError,
+        SignedRequest, canonical_json,
+    },
+    transport::{Loopback, LoopbackClient, Transport},
+};
+use serde_json::Value;
+use std::{sync::Arc, time::Duration};
+use tempfile::TempDir;
+use uuid::Uuid;
+
+/// How long to wait for a message forwarded to a connected client.
+const PUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A server wired to a loopback transport, with one operator able to approve users.
+struct Harness {
+    server: MessageUtils,
+    transport: Loopback,
+    server_key: String,
+    operator_secret: String,
+    _dir: TempDir,
+}
+
+/// A user talking to the harness through its own loopback client.
+struct User {
+    name: String,
+    secret: String,
+    public: String,
+    client: LoopbackClient,
+    /// Server key the user's replies must be signed with, following rotations the way
+    /// `GroupClient` does
+    server_key: String,
+    /// Frames pushed to this user that were not replies
+    pushed: Vec<Value>,
+}
+
+impl User {
+    /// The next frame pushed to this user, waiting for the forwarding tasks to run.
+    async fn next_push(&mut self) -> Result<Value> {
+        if !self.pushed.is_empty() {
+            return Ok(self.pushed.remove(0));
+        }
+        let frame = tokio::time::timeout(PUSH_TIMEOUT, self.client.recv())
+            .await
+            .context("nothing pushed")?
+            .context("transport closed")?;
+        Ok(serde_json::from_slice(&frame)?)
+    }
+
+    /// The next group message pushed to this user, checked against the pinned key.
+    async fn next_message(&mut self) -> Result<GroupMessage> {
+        let frame = self.next_push().await?;
+        if frame.get("action").and_then(Value::as_str) != Some("groupMessage") {
+            bail!("pushed frame is not a group message: {}", frame);
+        }
+        let signed = frame
+            .get("signature")
+            .and_then(Value::as_str)
+            .is_some_and(|sig| verify_detached(&self.server_key, &canonical_json(&frame), sig));
+        if !signed {
+            bail!("group message not signed with the pinned key");
+        }
+        let content = frame
+            .get("content")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        Ok(serde_json::from_str(content)?)
+    }
+
+    /// Pin the new key of a `serverKeyRotated` notice signed, and its hand-over signed,
+    /// with the pinned key.
+    fn follow_key_rotation(&mut self, frame: &Value) {
+        if frame.get("action").and_then(Value::as_str) != Some("serverKeyRotated") {
+            return;
+        }
+        let signed = frame
+            .get("signature")
+            .and_then(Value::as_str)
+            .is_some_and(|sig| verify_detached(&self.server_key, &canonical_json(frame), sig));
+        let content = frame
+            .get("content")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        if let Ok(Response::ServerKeyRotated(rotation)) =
+            Response::parse("serverKeyRotated", content)
+            && signed
+            && verify_detached(&self.server_key, &rotation.new_key, &rotation.handover)
+        {
+            self.server_key = rotation.new_key;
+        }
+    }
+}
+
+impl Harness {
+    async fn new() -> Result<Self> {
+        let dir = tempfile::tempdir()?;
+        let db_path = dir.path().join("groupd.db");
+        std::fs::File::create(&db_path)?;
+        let db = DbUtils::new(&db_path).await?;
+        let (operator_secret, operator_public) = generate_cert("root")?;
+        db.add_operator("root", &operator_public).await?;
+
+        let crypto = CryptoUtils::new(dir.path().join("keys"), "groupd".into(), "pw".into())?;
+        let server_key = crypto.generate_key_pair("groupd")?;
+        let transport = Loopback::new();
+        let server = MessageUtils::new(
+            "groupd".into(),
+            transport.reply_sender(),
+            db.clone(),
+            crypto,
+            Arc::new(db),
+            Arc::new(LocalBroker::new()),
+            GroupCreationPolicy::Approved,
+        );
+        Ok(Harness {
+            server,
+            transport,
+            server_key,
+            operator_secret,
+            _dir: dir,
+        })
+    }
+
+    fn user(&self, name: &str) -> Result<User> {
+        let (secret, public) = generate_cert(name)?;
+        Ok(User {
+            name: name.to_string(),
+            secret,
+            public,
+            client: self.transport.client(),
+            server_key: self.server_key.clone(),
+            pushed: Vec::new(),
+        })
+    }
+
+    /// Send `request` as `user`, signed with `secret` when the action requires it, let
+    /// the server handle it and return its verified reply.
+    async fn call_as(&mut self, user: &mut User, secret: &str, request: Request) -> Result<Reply> {
+        let signed = signed_request(secret, request)?;
+        self.submit(user, &serde_json::to_value(&signed)?).await
+    }
+
+    /// Send the raw request `frame` as `user`, let the server handle it and return its
+    /// verified reply.
+    async fn submit(&mut self, user: &mut User, frame: &Value) -> Result<Reply> {
+        let request_id = frame
+            .get("requestId")
+            .and_then(Value::as_str)
+            .context("request without requestId")?
+            .to_string();
+        user.client.send(serde_json::to_vec(frame)?)?;
+        let inbound = self
+            .transport
+            .next_request()
+            .await
+            .context("transport closed")?;
+        self.server.process_received_message(inbound).await;
+
+        // Loopback replies are queued before the handler returns
+        let mut reply = None;
+        while let Some(frame) = user.client.try_recv() {
+            let value: Value = serde_json::from_slice(&frame)?;
+            if value.get("context").and_then(Value::as_str) == Some(request_id.as_str()) {
+                reply = Some(value);
+            } else {
+                user.follow_key_rotation(&value);
+                user.pushed.push(value);
+            }
+        }
+        let value = reply.context("no reply")?;
+        let signature = value
+            .get("signature")
+            .and_then(Value::as_str)
+            .context("unsigned reply")?;
+        if !verify_detached(&user.server_key, &canonical_json(&value), signature) {
+            bail!("bad server signature on {}", value);
+        }
+        Ok(serde_json::from_value(value)?)
+    }
+
+    async fn call(&mut self, user: &mut User, request: Request) -> Result<Response> {
+        let secret = user.secret.clone();
+        let reply = self.call_as(user, &secret, request).await?;
+        if let Some(error) = reply.error() {
+            return Err(error.into());
+        }
+        Response::parse(&reply.action, &reply.content)
+    }
+
+    /// Register `user` and have the operator approve them.
+    async fn register(&mut self, user: &mut User) -> Result<()> {
+        let register = Request::Register {
+            username: user.name.clone(),
+            public_key: user.public.clone(),
+        };
+        assert_eq!(
+            self.call(user, register).await?,
+            Response::Status("pending".into())
+        );
+        let approve = Request::ApproveGroup {
+            username: user.name.clone(),
+        };
+        let operator_secret = self.operator_secret.clone();
+        let reply = self.call_as(user, &operator_secret, approve).await?;
+        assert_eq!(reply.error(), None);
+        Ok(())
+    }
+
+    /// Answer a fresh challenge to bind `user`'s client to their account.
+    async fn connect(&mut self, user: &mut User) -> Result<()> {
+        let nonce = match self.call(user, Request::GetChallenge).await? {
+            Response::Challenge(challenge) => challenge.nonce,
+            other => bail!("unexpected getChallenge reply: {:?}", other),
+        };
+        let connect = Request::Connect {
+            username: user.name.clone(),
+            nonce,
+        };
+        assert_eq!(
+            self.call(user, connect).await?,
+            Response::Status("success".into())
+        );
+        Ok(())
+    }
+
+    /// Create a public group owned by `user` and return its id.
+    async fn create_group(&mut self, user: &mut User, name: &str) -> Result<String> {
+        let create = Request::CreateGroup {
+            username: user.name.clone(),
+            group_name: name.into(),
+            is_public: true,
+            is_discoverable: true,
+        };
+        match self.call(user, create).await? {
+            Response::GroupCreated(created) => Ok(created.group_id),
+            other => bail!("unexpected createGroup reply: {:?}", other),
+        }
+    }
+}
+
+/// Wrap `request` with a fresh request id, signed with `secret` when the action requires
+/// it.
+fn signed_request(secret: &str, request: Request) -> Result<SignedRequest> {
+    let signed_request = request.is_signed();
+    let timestamped = signed_request && !matches!(request, Request::Connect { .. });
+    let mut signed = SignedRequest {
+        request,
+        version: Some(PROTOCOL_VERSION),
+        request_id: Some(Uuid::new_v4().to_string()),
+        timestamp: timestamped.then(|| Utc::now().timestamp_millis()),
+        signature: None,
+    };
+    if signed_request {
+        signed.signature = Some(sign_request(secret, &serde_json::to_value(&signed)?)?);
+    }
+    Ok(signed)
+}
+
+#[tokio::test]
+async fn register_approve_connect_send_fetch() -> Result<()> {
+    let mut harness = Harness::new().await?;
+    let mut alice = harness.user("alice")?;
+
+    // Nothing but serverInfo and getChallenge works before registering
+    let send = Request::SendGroup {
+        group_id: "g".into(),
+        ciphertext: "c".into(),
+    };
+    assert!(harness.call(&mut alice, send).await.is_err());
+
+    harness.register(&mut alice).await?;
+    // Nothing is left pending to approve
+    let approve = Request::ApproveGroup {
+        username: "alice".into(),
+    };
+    let operator_secret = harness.operator_secret.clone();
+    let reply = harness
+        .call_as(&mut alice, &operator_secret, approve)
+        .await?;
+    assert_eq!(
+        reply.error().map(|e| e.code),
+        Some(ErrorCode::NoSuchRegistration)
+    );
+    // An unknown operator is reported as such, even though only one is left
+    for (name, code) in [
+        ("nobody", ErrorCode::NoSuchOperator),
+        ("root", ErrorCode::LastOperator),
+    ] {
+        let remove = Request::RemoveOperator { name: name.into() };
+        let reply = harness
+            .call_as(&mut alice, &operator_secret, remove)
+            .await?;
+        assert_eq!(reply.error().map(|e| e.code), Some(code));
+    }
+
+    harness.connect(&mut alice).await?;
+    let group_id = harness.create_group(&mut alice, "rust").await?;
+
+    let send = Request::SendGroup {
+        group_id: group_id.clone(),
+        ciphertext: "hello".into(),
+    };
+    assert_eq!(
+        harness.call(&mut alice, send).await?,
+        Response::Status("success".into())
+    );
+    // The creator's session is subscribed to the new group
+    let pushed = alice.next_message().await?;
+    assert_eq!(pushed.sender, "alice");
+    assert_eq!(pushed.ciphertext, "hello");
+
+    let fetch = Request::FetchGroup {
+        group_id: group_id.clone(),
+        last_seen_id: "0".into(),
+    };
+    let Response::Messages(batch) = harness.call(&mut alice, fetch).await? else {
+        bail!("unexpected fetchGroup reply");
+    };
+    assert_eq!(batch.messages.len(), 1);
+    assert!(batch.messages[0].0.contains("hello"));
+
+    // Paging from the last id seen returns nothing new
+    let fetch = Request::FetchGroup {
+        group_id,
+        last_seen_id: batch.messages[0].1.clone(),
+    };
+    let Response::Messages(batch) = harness.call(&mut alice, fetch).await? else {
+        bail!("unexpected fetchGroup reply");
+    };
+    assert!(batch.messages.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn reconnect_replaces_session_and_disconnect_ends_it() -> Result<()> {
+    let mut harness = Harness::new().await?;
+    let mut alice = harness.user("alice")?;
+    harness.register(&mut alice).await?;
+    harness.connect(&mut alice).await?;
+    let group_id = harness.create_group(&mut alice, "rust").await?;
+
+    // Connecting again must not deliver every message twice
+    harness.connect(&mut alice).await?;
+    for ciphertext in ["one", "two"] {
+        let send = Request::SendGroup {
+            group_id: group_id.clone(),
+            ciphertext: ciphertext.into(),
+        };
+        harness.call(&mut alice, send).await?;
+    }
+    assert_eq!(alice.next_message().await?.ciphertext, "one");
+    assert_eq!(alice.next_message().await?.ciphertext, "two");
+
+    assert_eq!(
+        harness.call(&mut alice, Request::Disconnect).await?,
+        Response::Status("success".into())
+    );
+    let send = Request::SendGroup {
+        group_id,
+        ciphertext: "three".into(),
+    };
+    let error = harness.call(&mut alice, send).await.unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<ServerError>().map(|e| e.code),
+        Some(ErrorCode::NotConnected)
+    );
+    assert!(alice.client.try_recv().is_none());
+    Ok(())
+}
+
+#[tokio::test]
+async fn replayed_request_is_rejected_even_when_rearmored() -> Result<()> {
+    let mut harness = Harness::new().await?;
+    let mut alice = harness.user("alice")?;
+    harness.register(&mut alice).await?;
+    harness.connect(&mut alice).await?;
+    let group_id = harness.create_group(&mut alice, "rust").await?;
+
+    let send = Request::SendGroup {
+        group_id,
+        ciphertext: "hello".into(),
+    };
+    let signed = signed_request(&alice.secret, send)?;
+    let frame = serde_json::to_value(&signed)?;
+    assert_eq!(harness.submit(&mut alice, &frame).await?.error(), None);
+
+    // The same request with its signature armored differently is still a replay
+    let replayed = SignedRequest {
+        signature: signed.signature.as_ref().map(|sig| format!("{}\n", sig)),
+        ..signed
+    };
+    let reply = harness
+        .submit(&mut alice, &serde_json::to_value(&replayed)?)
+        .await?;
+    assert_eq!(reply.error().map(|e| e.code), Some(ErrorCode::StaleRequest));
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_pinning_the_old_key_follows_a_rotation() -> Result<()> {
+    let mut harness = Harness::new().await?;
+    let mut alice = harness.user("alice")?;
+    // Bob and Carol are offline during the rotation and still pin the old key
+    let mut bob = harness.user("bob")?;
+    let mut carol = harness.user("carol")?;
+    harness.register(&mut bob).await?;
+
+    let operator_secret = harness.operator_secret.clone();
+    let reply = harness
+        .call_as(&mut alice, &operator_secret, Request::RotateServerKey)
+        .await?;
+    assert_eq!(reply.error(), None);
+    assert_ne!(alice.server_key, harness.server_key);
+
+    let Response::ServerInfo(info) = harness.call(&mut carol, Request::ServerInfo).await? else {
+        bail!("unexpected serverInfo reply");
+    };
+    assert_eq!(info.public_key, alice.server_key);
+    assert_eq!(carol.server_key, alice.server_key);
+
+    harness.connect(&mut bob).await?;
+    assert_eq!(bob.server_key, alice.server_key);
+
+    // Rotating again would drop the hand-over clients like Bob rely on
+    let reply = harness
+        .call_as(&mut alice, &operator_secret, Request::RotateServerKey)
+        .await?;
+    assert_eq!(
+        reply.error().map(|e| e.code),
+        Some(ErrorCode::RotationInProgress)
+    );
+    Ok(())
+}